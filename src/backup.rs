@@ -0,0 +1,168 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{backup::Backup, Connection, DatabaseName, OpenFlags};
+
+use crate::database;
+
+/// Default number of backups to keep. Older ones are removed after a new
+/// backup has been created.
+pub const DEFAULT_BACKUPS: usize = 5;
+
+const BACKUP_SUFFIX: &str = ".bak";
+
+/// Returns the current UTC time formatted as `YYYYMMDD-HHMMSS`.
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default() as i64;
+
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Convert days since epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn backup_prefix(db_file: &Path) -> String {
+    format!(
+        "{}.",
+        db_file.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// Lists the existing backups of `db_file`, oldest first.
+pub fn list_backups(db_file: &Path) -> Vec<PathBuf> {
+    let dir = db_file.parent().unwrap_or_else(|| Path::new("."));
+    let prefix = backup_prefix(db_file);
+
+    let mut backups = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                name.starts_with(&prefix) && name.ends_with(BACKUP_SUFFIX)
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    // The timestamp format sorts chronologically.
+    backups.sort();
+
+    backups
+}
+
+/// Copies the database via SQLite's online backup API into a timestamped
+/// file next to the database and removes all but the last `keep` backups.
+pub fn create_backup(conn: &Connection, db_file: &Path, keep: usize) -> rusqlite::Result<PathBuf> {
+    let backup_file = db_file.with_file_name(format!(
+        "{}{}{}",
+        backup_prefix(db_file),
+        timestamp(),
+        BACKUP_SUFFIX
+    ));
+
+    conn.backup(DatabaseName::Main, &backup_file, None)?;
+
+    let backups = list_backups(db_file);
+    if backups.len() > keep {
+        for old_backup in &backups[..backups.len() - keep] {
+            let _ = fs::remove_file(old_backup);
+        }
+    }
+
+    Ok(backup_file)
+}
+
+/// Copies the database `src_file` into the in-memory database `name` of
+/// `conn`. The file is opened read-only and never modified.
+pub fn copy_into_memory(
+    src_file: &Path,
+    conn: &mut Connection,
+    name: DatabaseName,
+) -> rusqlite::Result<()> {
+    let src = Connection::open_with_flags(src_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    // Backups into in-memory databases require equal page sizes.
+    let page_size: i64 = src.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+    conn.pragma_update(Some(name), "page_size", page_size)?;
+
+    let backup = Backup::new_with_names(&src, DatabaseName::Main, conn, name)?;
+    backup.run_to_completion(100, Duration::ZERO, None)
+}
+
+/// Puts the given backup back into place of `db_file`.
+pub fn restore_backup(db_file: &Path, backup_file: &Path) -> rusqlite::Result<()> {
+    // Neither file is created if it doesn't exist.
+    let backup = Connection::open_with_flags(backup_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut conn = database::open_existing(db_file)?;
+    let restore = Backup::new(&backup, &mut conn)?;
+    restore.run_to_completion(100, Duration::ZERO, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn title(conn: &Connection) -> String {
+        conn.query_row("SELECT title FROM books", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn prunes_old_backups_and_restores_one() {
+        let dir = temp_dir("backup");
+        let db_file = dir.join("explorer-3.db");
+        let conn = Connection::open(&db_file).unwrap();
+        conn.execute_batch("CREATE TABLE books (title TEXT); INSERT INTO books VALUES ('Old');")
+            .unwrap();
+
+        // Backups from earlier runs, named after their (older) timestamps.
+        let old_backups = (1..=4)
+            .map(|day| dir.join(format!("explorer-3.db.2022030{}-120000.bak", day)))
+            .collect::<Vec<_>>();
+        for old_backup in &old_backups {
+            conn.backup(DatabaseName::Main, old_backup, None).unwrap();
+        }
+
+        let backup_file = create_backup(&conn, &db_file, 3).unwrap();
+
+        assert_eq!(
+            list_backups(&db_file),
+            vec![
+                old_backups[2].clone(),
+                old_backups[3].clone(),
+                backup_file.clone()
+            ]
+        );
+
+        conn.execute("UPDATE books SET title = 'New'", []).unwrap();
+        restore_backup(&db_file, &backup_file).unwrap();
+
+        assert_eq!(title(&conn), "Old");
+    }
+}
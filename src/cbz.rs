@@ -0,0 +1,179 @@
+use std::{
+    fs,
+    io::{Read, Seek},
+};
+
+use quick_xml::{events::Event, Reader};
+use zip::ZipArchive;
+
+use crate::{
+    error::Result,
+    format::{Author, BookMetadata, Collection, Series},
+    xml,
+};
+
+const COMIC_INFO_FILE: &str = "ComicInfo.xml";
+
+/// Returns the name of the ComicInfo.xml entry, preferring the one at the
+/// top level of the archive.
+fn find_comic_info<R: Read + Seek>(archive: &ZipArchive<R>) -> Option<String> {
+    let mut entries = archive
+        .file_names()
+        .filter(|entry| {
+            let name = entry.rsplit('/').next().unwrap_or_default();
+            name.eq_ignore_ascii_case(COMIC_INFO_FILE)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.matches('/').count());
+    entries.first().map(|entry| entry.to_string())
+}
+
+/// Splits one of ComicInfo's comma-separated lists.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn parse_comic_info(xml: &str) -> Result<BookMetadata> {
+    let mut book_meta = BookMetadata::new();
+
+    let mut reader = Reader::from_str(xml);
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    let mut text = String::new();
+    let mut series = String::new();
+    let mut number = String::new();
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(_) => {
+                depth += 1;
+                text.clear();
+            }
+            Event::Text(ref e) => text.push_str(&xml::decode_text(e.escaped())),
+            Event::CData(ref e) => text.push_str(&String::from_utf8_lossy(e)),
+            // The fields are the children of the ComicInfo element.
+            Event::End(ref e) if depth == 2 => {
+                let value = text.trim();
+                match e.local_name() {
+                    b"Title" => book_meta.title = value.to_string(),
+                    b"Series" => series = value.to_string(),
+                    b"Number" => number = value.to_string(),
+                    b"Writer" | b"Penciller" => {
+                        let role = if e.local_name() == b"Writer" {
+                            "aut"
+                        } else {
+                            "ill"
+                        };
+                        for name in split_list(value) {
+                            book_meta.authors.push(Author {
+                                name: name.to_string(),
+                                firstauthor: String::new(),
+                                role: role.to_string(),
+                                is_creator: role == "aut",
+                            });
+                        }
+                    }
+                    b"Genre" => {
+                        for genre in split_list(value) {
+                            if !book_meta.genres.iter().any(|g| g == genre) {
+                                book_meta.genres.push(genre.to_string());
+                            }
+                        }
+                    }
+                    b"LanguageISO" if !value.is_empty() => {
+                        book_meta.languages.push(value.to_string())
+                    }
+                    b"Publisher" => book_meta.publisher = value.to_string(),
+                    b"Summary" => book_meta.description = value.to_string(),
+                    _ => (),
+                }
+                depth -= 1;
+                text.clear();
+            }
+            Event::End(_) => depth -= 1,
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    if !series.is_empty() {
+        book_meta.series = Series {
+            name: series.clone(),
+            index: Series::parse_index(&number),
+        };
+        book_meta.collections.push(Collection {
+            name: series,
+            collection_type: "series".to_string(),
+            index: book_meta.series.index,
+        });
+    }
+
+    Ok(book_meta)
+}
+
+fn read_cbz_metadata<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<BookMetadata> {
+    // Comics without ComicInfo.xml have no metadata, which makes the fixers
+    // leave their entries alone.
+    let comic_info = match find_comic_info(archive) {
+        Some(comic_info) => comic_info,
+        None => return Ok(BookMetadata::new()),
+    };
+
+    let mut xml = String::new();
+    archive.by_name(&comic_info)?.read_to_string(&mut xml)?;
+    parse_comic_info(xml.trim_start_matches('\u{feff}'))
+}
+
+/// Reads the metadata of a comic book archive from its ComicInfo.xml.
+pub fn get_cbz_metadata(filename: &str) -> Result<BookMetadata> {
+    let mut archive = ZipArchive::new(fs::File::open(filename)?)?;
+
+    read_cbz_metadata(&mut archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{archive, authors};
+
+    #[test]
+    fn reads_comic_info() {
+        let mut archive = archive(&[
+            ("001.jpg", ""),
+            (
+                "extras/comicinfo.xml",
+                "<ComicInfo><Series>Wrong</Series></ComicInfo>",
+            ),
+            ("ComicInfo.xml", include_str!("../tests/cbz/ComicInfo.xml")),
+        ]);
+
+        let book_meta = read_cbz_metadata(&mut archive).unwrap();
+        assert_eq!(book_meta.title, "The Long & Winding Road");
+        assert_eq!(book_meta.series.name, "Space Cadets");
+        assert_eq!(book_meta.series.index, Some(12.5));
+        assert_eq!(
+            authors(&book_meta),
+            [
+                ("Ann Writer", "", "aut"),
+                ("Bob Scribe", "", "aut"),
+                ("Cy Pencil", "", "ill")
+            ]
+        );
+        assert_eq!(book_meta.main_authors().len(), 2);
+        assert_eq!(book_meta.genres, ["Science Fiction", "Humor"]);
+        assert_eq!(book_meta.languages, ["en"]);
+        assert_eq!(book_meta.description, "Nothing <b>happens</b>.");
+    }
+
+    #[test]
+    fn accepts_comics_without_comic_info() {
+        let mut archive = archive(&[("001.jpg", ""), ("002.jpg", "")]);
+
+        let book_meta = read_cbz_metadata(&mut archive).unwrap();
+        assert!(book_meta.is_empty());
+    }
+}
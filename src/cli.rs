@@ -0,0 +1,317 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    backup,
+    database::{self, FixOptions, Fixer, GenrePolicy},
+    device::{self, PathMap},
+};
+
+pub const USAGE: &str = "\
+Usage: pbdbfixer [COMMAND] [OPTIONS]
+
+Commands:
+  fix                 Fix the database (default)
+  check               Show what would be fixed without changing anything
+  inspect [BOOK-ID]   Show the database entries (and the metadata of a book)
+  export [FILE]       Export the database entries as CSV
+  restore [NUMBER]    List the backups or restore one of them
+  undo [RUN-ID]       List the journaled runs or revert one of them
+
+Options:
+  --db <PATH>         Use the database at PATH
+  --device <PATH>     Use the reader mounted at PATH (or `auto` to search
+                      for it; done anyway if the database isn't found)
+  --sdcard <PATH>     Use the reader's SD card mounted at PATH
+  --only <FIXERS>     Only run the given fixers (comma separated)
+  --skip <FIXERS>     Don't run the given fixers (comma separated)
+  --ghost-runs <N>    Remove deleted books only after they have been missing
+                      for N runs (default: 3)
+  --backups <N>       Keep the last N backups of the database (default: 5)
+  --genres <POLICY>   add: only add missing genres (default), sync: also
+                      remove genres the book's subjects don't list anymore,
+                      replace: make the genres match the subjects exactly
+  --series-suffix     Append series and index to the titles of books with a
+                      fractional series index, e.g. \"Title [Series 2.5]\"
+  --dry-run           Same as the check command (only with fix)
+  -v, --verbose       Print every single change
+  -h, --help          Print this help
+
+Fixers: titles, authors, sorting, genres, series, relink, ghosts
+
+Exit codes: 0 = nothing to fix, 1 = errors, 2 = entries fixed (or to be fixed)";
+
+pub enum Command {
+    Fix,
+    Check,
+    Inspect(Option<i32>),
+    Export(Option<PathBuf>),
+    Restore(Option<String>),
+    Undo(Option<String>),
+    Help,
+}
+
+pub struct Options {
+    pub command: Command,
+    pub db_file: PathBuf,
+    pub fix_options: FixOptions,
+    pub verbose: bool,
+}
+
+fn parse_fixers(list: &str) -> Result<Vec<Fixer>, String> {
+    list.split(',')
+        .map(|name| Fixer::from_name(name.trim()).ok_or_else(|| format!("Unknown fixer: {}", name)))
+        .collect()
+}
+
+/// Parses the command line arguments (without the program name).
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut db_file = None;
+    let mut device_root = None;
+    let mut sdcard_root = None;
+    let mut fixers = Fixer::ALL.to_vec();
+    let mut ghost_runs = database::DEFAULT_GHOST_RUNS;
+    let mut backups = backup::DEFAULT_BACKUPS;
+    let mut genre_policy = GenrePolicy::Add;
+    let mut series_suffix = false;
+    let mut dry_run = false;
+    let mut verbose = false;
+    let mut command = None;
+    let mut operand = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--db" => {
+                let path = args.next().ok_or("--db requires a path")?;
+                db_file = Some(PathBuf::from(path));
+            }
+            "--device" => {
+                let path = args.next().ok_or("--device requires a path")?;
+                device_root = Some(path.to_string());
+            }
+            "--sdcard" => {
+                let path = args.next().ok_or("--sdcard requires a path")?;
+                sdcard_root = Some(PathBuf::from(path));
+            }
+            "--only" => {
+                let list = args.next().ok_or("--only requires a list of fixers")?;
+                fixers = parse_fixers(list)?;
+            }
+            "--skip" => {
+                let list = args.next().ok_or("--skip requires a list of fixers")?;
+                let skipped = parse_fixers(list)?;
+                fixers.retain(|fixer| !skipped.contains(fixer));
+            }
+            "--ghost-runs" => {
+                let num = args.next().ok_or("--ghost-runs requires a number")?;
+                ghost_runs = match num.parse() {
+                    Ok(num) if num >= 1 => num,
+                    _ => return Err(format!("Invalid number of runs: {}", num)),
+                };
+            }
+            "--backups" => {
+                let num = args.next().ok_or("--backups requires a number")?;
+                backups = match num.parse() {
+                    Ok(num) if num >= 1 => num,
+                    _ => return Err(format!("Invalid number of backups: {}", num)),
+                };
+            }
+            "--genres" => {
+                let name = args.next().ok_or("--genres requires a policy")?;
+                genre_policy = GenrePolicy::from_name(name)
+                    .ok_or_else(|| format!("Unknown genre policy: {}", name))?;
+            }
+            "--series-suffix" => series_suffix = true,
+            "--dry-run" => dry_run = true,
+            "-v" | "--verbose" => verbose = true,
+            "-h" | "--help" => command = Some("help".to_string()),
+            arg if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            arg if command.is_none() => command = Some(arg.to_string()),
+            arg if operand.is_none() => operand = Some(arg.to_string()),
+            arg => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let command = match (command.as_deref(), operand) {
+        (None, None) | (Some("fix"), None) if dry_run => Command::Check,
+        (None, None) | (Some("fix"), None) => Command::Fix,
+        (Some("check"), None) => {
+            dry_run = true;
+            Command::Check
+        }
+        (Some("inspect"), None) => Command::Inspect(None),
+        (Some("inspect"), Some(id)) => Command::Inspect(Some(
+            id.parse().map_err(|_| format!("Invalid book id: {}", id))?,
+        )),
+        (Some("export"), file) => Command::Export(file.map(PathBuf::from)),
+        (Some("restore"), selection) => Command::Restore(selection),
+        (Some("undo"), run_id) => Command::Undo(run_id),
+        (Some("help"), _) => Command::Help,
+        (Some(command), None) => return Err(format!("Unknown command: {}", command)),
+        (_, Some(operand)) => return Err(format!("Unexpected argument: {}", operand)),
+    };
+    if dry_run && !matches!(command, Command::Check | Command::Help) {
+        return Err("--dry-run can only be used with the fix command".to_string());
+    }
+
+    let device_root = match device_root.as_deref() {
+        Some("auto") => Some(discover_device()?),
+        Some(path) => Some(PathBuf::from(path)),
+        None if db_file.is_none()
+            && !cfg!(target_arch = "arm")
+            && !Path::new(database::DATABASE_FILE).exists() =>
+        {
+            discover_device().ok()
+        }
+        None => None,
+    };
+
+    let db_file = match (db_file, &device_root) {
+        (Some(db_file), _) => db_file,
+        (None, Some(root)) => device::database_file(root),
+        (None, None) => PathBuf::from(database::DATABASE_FILE),
+    };
+
+    Ok(Options {
+        command,
+        db_file,
+        fix_options: FixOptions {
+            fixers,
+            dry_run,
+            path_map: PathMap::new(device_root, sdcard_root),
+            ghost_runs,
+            genre_policy,
+            series_suffix,
+            backups,
+        },
+        verbose,
+    })
+}
+
+/// Returns the root of the only mounted reader.
+fn discover_device() -> Result<PathBuf, String> {
+    let mut roots = device::discover();
+    match roots.len() {
+        0 => Err("No mounted reader found".to_string()),
+        1 => {
+            let root = roots.remove(0);
+            eprintln!("Using the reader mounted at {}", root.display());
+            Ok(root)
+        }
+        _ => Err(format!(
+            "Several mounted readers found, please choose one with --device:\n{}",
+            roots
+                .iter()
+                .map(|root| format!("  {}", root.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` with an explicit database, so no reader is searched.
+    fn parse_args(args: &str) -> Result<Options, String> {
+        let mut args = args
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        args.splice(0..0, ["--db".to_string(), "test.db".to_string()]);
+        parse(&args)
+    }
+
+    fn command(args: &str) -> Command {
+        parse_args(args).ok().unwrap().command
+    }
+
+    fn error(args: &str) -> String {
+        parse_args(args).err().unwrap()
+    }
+
+    #[test]
+    fn parses_the_commands() {
+        assert!(matches!(command(""), Command::Fix));
+        assert!(matches!(command("fix"), Command::Fix));
+        assert!(matches!(command("check"), Command::Check));
+        assert!(matches!(command("inspect"), Command::Inspect(None)));
+        assert!(matches!(command("inspect 12"), Command::Inspect(Some(12))));
+        assert!(matches!(command("export"), Command::Export(None)));
+        assert!(
+            matches!(command("export books.csv"), Command::Export(Some(file)) if file == Path::new("books.csv"))
+        );
+        assert!(matches!(command("restore"), Command::Restore(None)));
+        assert!(
+            matches!(command("restore 2"), Command::Restore(Some(selection)) if selection == "2")
+        );
+        assert!(matches!(command("undo"), Command::Undo(None)));
+        assert!(matches!(command("undo 3"), Command::Undo(Some(run_id)) if run_id == "3"));
+        assert!(matches!(command("help"), Command::Help));
+        assert!(matches!(command("fix -h"), Command::Help));
+
+        assert_eq!(error("frobnicate"), "Unknown command: frobnicate");
+        assert_eq!(error("inspect twelve"), "Invalid book id: twelve");
+        assert_eq!(error("--frobnicate"), "Unknown option: --frobnicate");
+    }
+
+    #[test]
+    fn rejects_extra_operands() {
+        assert_eq!(error("fix now"), "Unexpected argument: now");
+        assert_eq!(error("check now"), "Unexpected argument: now");
+        assert_eq!(error("inspect 1 2"), "Unexpected argument: 2");
+        assert_eq!(error("undo 1 2"), "Unexpected argument: 2");
+        assert_eq!(error("--only"), "--only requires a list of fixers");
+    }
+
+    #[test]
+    fn accepts_dry_run_only_for_fixing() {
+        for args in ["--dry-run", "fix --dry-run", "check", "check --dry-run"] {
+            let options = parse_args(args).ok().unwrap();
+            assert!(matches!(options.command, Command::Check), "{}", args);
+            assert!(options.fix_options.dry_run, "{}", args);
+        }
+        assert!(!parse_args("fix").ok().unwrap().fix_options.dry_run);
+
+        for command in ["inspect", "export", "restore", "undo"] {
+            assert_eq!(
+                error(&format!("{} --dry-run", command)),
+                "--dry-run can only be used with the fix command"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_the_number_of_backups() {
+        let backups = |args| parse_args(args).ok().unwrap().fix_options.backups;
+
+        assert_eq!(backups(""), backup::DEFAULT_BACKUPS);
+        assert_eq!(backups("--backups 10"), 10);
+        assert_eq!(error("--backups 0"), "Invalid number of backups: 0");
+        assert_eq!(error("--backups"), "--backups requires a number");
+    }
+
+    #[test]
+    fn selects_the_fixers() {
+        let fixers = |args| parse_args(args).ok().unwrap().fix_options.fixers;
+
+        assert_eq!(fixers(""), Fixer::ALL);
+        assert_eq!(
+            fixers("--only titles,authors,genres --skip authors"),
+            [Fixer::Titles, Fixer::Genres]
+        );
+        assert_eq!(
+            fixers("--skip relink,ghosts"),
+            [
+                Fixer::Titles,
+                Fixer::Authors,
+                Fixer::Sorting,
+                Fixer::Genres,
+                Fixer::Series
+            ]
+        );
+        assert_eq!(error("--only titles,colours"), "Unknown fixer: colours");
+        assert_eq!(error("--skip colours"), "Unknown fixer: colours");
+    }
+}
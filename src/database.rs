@@ -1,35 +1,163 @@
-use rusqlite::{named_params, Connection, Transaction};
+use std::path::{Path, PathBuf};
 
-use crate::epub;
+use rusqlite::{named_params, Connection, DatabaseName, OpenFlags, Transaction};
 
-const DATABASE_FILE: &str = "/mnt/ext1/system/explorer-3/explorer-3.db";
+use crate::{
+    backup,
+    device::{PathMap, LOCAL_STORAGES},
+    error::Result,
+    format::{self, Format},
+    journal::{self, BookRef, Kind},
+    schema::SchemaProfile,
+};
+
+pub const DATABASE_FILE: &str = "/mnt/ext1/system/explorer-3/explorer-3.db";
+
+/// Default number of runs a ghost book has to be missing before it is removed.
+pub const DEFAULT_GHOST_RUNS: i64 = 3;
+
+/// The individual fixes which can be selected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fixer {
+    Titles,
+    Authors,
+    Sorting,
+    Genres,
+    Series,
+    Relink,
+    Ghosts,
+}
+
+impl Fixer {
+    pub const ALL: [Fixer; 7] = [
+        Fixer::Titles,
+        Fixer::Authors,
+        Fixer::Sorting,
+        Fixer::Genres,
+        Fixer::Series,
+        Fixer::Relink,
+        Fixer::Ghosts,
+    ];
+
+    /// Whether the fixer compares the database entries with the metadata of
+    /// the book files.
+    pub fn reads_books(&self) -> bool {
+        !matches!(self, Fixer::Relink | Fixer::Ghosts)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "titles" => Some(Fixer::Titles),
+            "authors" => Some(Fixer::Authors),
+            "sorting" => Some(Fixer::Sorting),
+            "genres" => Some(Fixer::Genres),
+            "series" => Some(Fixer::Series),
+            "relink" => Some(Fixer::Relink),
+            "ghosts" => Some(Fixer::Ghosts),
+            _ => None,
+        }
+    }
+}
+
+/// How the genres of a book are reconciled with the subjects of its EPUB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GenrePolicy {
+    /// Only add missing genres.
+    Add,
+    /// Add missing genres and remove those the EPUB listed in earlier runs
+    /// but doesn't list anymore. Genres added on the device are kept.
+    Sync,
+    /// Make the genres match the EPUB's subjects exactly.
+    Replace,
+}
+
+impl GenrePolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "add" => Some(GenrePolicy::Add),
+            "sync" => Some(GenrePolicy::Sync),
+            "replace" => Some(GenrePolicy::Replace),
+            _ => None,
+        }
+    }
+}
+
+pub struct FixOptions {
+    pub fixers: Vec<Fixer>,
+    pub dry_run: bool,
+    pub path_map: PathMap,
+    /// Number of runs a ghost book has to be missing before it is removed.
+    pub ghost_runs: i64,
+    pub genre_policy: GenrePolicy,
+    /// Append the series and the index to the titles of books with a
+    /// fractional series index.
+    pub series_suffix: bool,
+    /// Number of database backups to keep.
+    pub backups: usize,
+}
 
 pub struct BookEntry {
-    id: i32,
-    filepath: String,
-    author: String,
-    firstauthor: String,
-    genre: String,
-    first_author_letter: String,
-    series: String,
+    pub id: i32,
+    pub filepath: String,
+    pub title: String,
+    pub sort_title: String,
+    pub first_title_letter: String,
+    pub author: String,
+    pub firstauthor: String,
+    pub genres: Vec<String>,
+    pub first_author_letter: String,
+    pub series: String,
+    pub numinseries: i32,
 }
 
-fn get_epubs_from_database(tx: &Transaction) -> Vec<BookEntry> {
-    let mut book_entries = Vec::new();
+/// Opens an existing database for reading and writing. Unlike
+/// `Connection::open`, a mistyped path doesn't create a new empty database.
+pub fn open_existing(db_file: &Path) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags(
+        db_file,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+}
 
-    let version: i32 = tx
-        .query_row(r#"SELECT id FROM version"#, [], |r| r.get(0))
-        .unwrap();
+/// Returns the mount points of the removable storages which are referenced
+/// in the database but currently not mounted.
+fn get_unmounted_storages(conn: &Connection, path_map: &PathMap) -> Result<Vec<&'static str>> {
+    let mut unmounted = Vec::new();
 
-    let books_or_files = match version {
-        x if x >= 38 => "files",
-        _ => "books",
-    };
+    for (storage_id, prefix, removable) in LOCAL_STORAGES {
+        if !removable || path_map.is_mounted(prefix) {
+            continue;
+        }
+        let num: i32 = conn.query_row(
+            r#"SELECT COUNT(*) FROM folders WHERE storageid = ?1"#,
+            [storage_id],
+            |r| r.get(0),
+        )?;
+        if num > 0 {
+            unmounted.push(*prefix);
+        }
+    }
+
+    Ok(unmounted)
+}
+
+/// Returns the entries of the database in a supported format stored on one
+/// of the given storages. Entries whose file location cannot be determined are returned
+/// separately as failed books.
+fn get_books_from_database(
+    conn: &Connection,
+    profile: &SchemaProfile,
+    path_map: &PathMap,
+    storage_ids: &[i32],
+) -> Result<(Vec<BookEntry>, Vec<FailedBook>)> {
+    let mut book_entries = Vec::new();
+    let mut failed_books = Vec::new();
 
     let stmt_str = format!(
         r#"
     SELECT books.id, folders.name, files.filename, books.firstauthor,
-      books.author, genres.name, first_author_letter, series
+      books.author, genres.name, first_author_letter, series, books.title,
+      books.sort_title, books.first_title_letter, books.numinseries
       FROM books_impl books JOIN files
         ON books.id = files.book_id
         JOIN folders
@@ -38,235 +166,1181 @@ fn get_epubs_from_database(tx: &Transaction) -> Vec<BookEntry> {
           ON books.id = btg.bookid
         LEFT OUTER JOIN genres
           ON genres.id = btg.genreid
-      WHERE files.storageid = 1 AND {}.ext = 'epub'
+      WHERE files.storageid IN ({}) AND {}.ext IN ({})
       ORDER BY books.id"#,
-        &books_or_files
+        storage_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        profile.ext_table,
+        format::EXTENSIONS
+            .iter()
+            .map(|ext| format!("'{}'", ext))
+            .collect::<Vec<_>>()
+            .join(", ")
     );
 
-    let mut stmt = tx.prepare(&stmt_str).unwrap();
+    let mut stmt = conn.prepare(&stmt_str)?;
 
-    let mut rows = stmt.query([]).unwrap();
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        let book_id: i32 = row.get(0)?;
+        let genre: Option<String> = row.get(5).unwrap_or_default();
+
+        // Books with several genres appear in several rows.
+        if let Some(entry) = book_entries
+            .last_mut()
+            .filter(|e: &&mut BookEntry| e.id == book_id)
+        {
+            entry.genres.extend(genre);
+            continue;
+        }
 
-    while let Some(row) = rows.next().unwrap() {
-        let book_id: i32 = row.get(0).unwrap();
-        let prefix: String = row.get(1).unwrap();
-        let filename: String = row.get(2).unwrap();
-        let filepath = format!("{}/{}", prefix, filename);
+        let prefix: Option<String> = row.get(1).unwrap_or_default();
+        let filename: Option<String> = row.get(2).unwrap_or_default();
+        let filepath = match (prefix, filename) {
+            (_, Some(filename)) if Format::from_filename(&filename).is_none() => continue,
+            (Some(prefix), Some(filename)) => {
+                path_map.to_local(&format!("{}/{}", prefix, filename))
+            }
+            _ if failed_books.last().map(|f: &FailedBook| f.book_id) == Some(book_id) => continue,
+            _ => {
+                failed_books.push(FailedBook {
+                    book_id,
+                    book: String::new(),
+                    reason: "missing folder or file name".to_string(),
+                });
+                continue;
+            }
+        };
         let firstauthor: String = row.get(3).unwrap_or_default();
         let author: String = row.get(4).unwrap_or_default();
-        let genre: String = row.get(5).unwrap_or_default();
         let first_author_letter = row.get(6).unwrap_or_default();
         let series: String = row.get(7).unwrap_or_default();
+        let title: String = row.get(8).unwrap_or_default();
+        let sort_title: String = row.get(9).unwrap_or_default();
+        let first_title_letter: String = row.get(10).unwrap_or_default();
+        let numinseries: i32 = row.get(11).unwrap_or_default();
 
         let entry = BookEntry {
             id: book_id,
             filepath,
+            title,
+            sort_title,
+            first_title_letter,
             firstauthor,
             author,
-            genre,
+            genres: genre.into_iter().collect(),
             first_author_letter,
             series,
+            numinseries,
         };
 
         book_entries.push(entry);
     }
 
-    book_entries
+    Ok((book_entries, failed_books))
+}
+
+fn get_ghost_books_from_db(tx: &Transaction) -> Result<Vec<(i32, String)>> {
+    let mut stmt = tx.prepare(
+        r#"
+            SELECT books.id, books.title
+              FROM books_impl books
+                LEFT OUTER JOIN files
+                  ON books.id = files.book_id
+              WHERE files.filename is NULL
+              ORDER BY books.id"#,
+    )?;
+
+    let ghost_books = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(ghost_books)
 }
 
-fn remove_ghost_books_from_db(tx: &Transaction) -> usize {
-    let mut stmt = tx
-        .prepare(
+/// Returns pairs of ghost books and books with a file which are most likely
+/// the same e-book after it has been moved or renamed: either their hashes
+/// or their title, author and size are equal. Only unambiguous pairs are
+/// returned: (ghost id, ghost title, new id)
+fn get_moved_books_from_db(
+    tx: &Transaction,
+    profile: &SchemaProfile,
+) -> Result<Vec<(i32, String, i32)>> {
+    let hash_match = match profile.hash_column {
+        Some(hash_column) => format!(
             r#"
-            DELETE FROM books_impl
-            WHERE id IN (
-              SELECT books.id
-                FROM books_impl books
-                  LEFT OUTER JOIN files
-                    ON books.id = files.book_id
-                WHERE files.filename is NULL
-            )"#,
-        )
-        .unwrap();
+                EXISTS (
+                  SELECT 1
+                    FROM {table} ghost_hash
+                      JOIN {table} new_hash
+                        ON ghost_hash.{column} = new_hash.{column}
+                    WHERE ghost_hash.book_id = ghost.id
+                      AND new_hash.book_id = new.id
+                      AND ghost_hash.{column} <> ''
+                ) OR"#,
+            table = profile.hash_table,
+            column = hash_column
+        ),
+        None => String::new(),
+    };
 
-    let num = stmt.execute([]).unwrap();
+    let mut stmt = tx.prepare(&format!(
+        r#"
+            SELECT ghost.id, ghost.title, new.id
+              FROM books_impl ghost
+                JOIN books_impl new
+                  ON new.id <> ghost.id
+              WHERE ghost.id NOT IN (SELECT book_id FROM files WHERE filename IS NOT NULL)
+                AND new.id IN (SELECT book_id FROM files WHERE filename IS NOT NULL)
+                AND ({}
+                  (new.title = ghost.title
+                    AND new.author = ghost.author
+                    AND new.size = ghost.size
+                    AND new.size > 0))
+              ORDER BY ghost.id"#,
+        hash_match
+    ))?;
 
-    tx.execute(
-        r#"DELETE FROM books_settings WHERE bookid NOT IN ( SELECT id FROM books_impl )"#,
-        [],
-    )
-    .unwrap();
+    let pairs = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                row.get(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<(i32, String, i32)>>>()?;
 
-    let version: i32 = tx
-        .query_row(r#"SELECT id FROM version"#, [], |r| r.get(0))
-        .unwrap();
-    if version >= 37 {
-        tx.execute(
-            r#"DELETE FROM books_fast_hashes WHERE book_id NOT IN ( SELECT id FROM books_impl )"#,
-            [],
-        )
-        .unwrap();
-    } else {
+    Ok(pairs
+        .iter()
+        .filter(|(ghost_id, _, new_id)| {
+            pairs.iter().filter(|(g, _, _)| g == ghost_id).count() == 1
+                && pairs.iter().filter(|(_, _, n)| n == new_id).count() == 1
+        })
+        .cloned()
+        .collect())
+}
+
+/// Moves the settings, bookshelf entries, genres, social data and recorded
+/// subjects of book `old_id` to book `new_id`. Rows already present for the
+/// new book are kept. Returns the tables and the number of rows of the old
+/// book which could not be moved for that reason.
+fn relink_book(
+    tx: &Transaction,
+    run_id: i64,
+    old_id: i32,
+    new_id: i32,
+) -> Result<Vec<(&'static str, usize)>> {
+    let mut conflicts = Vec::new();
+    for table in [
+        "books_settings",
+        "bookshelfs_books",
+        "booktogenre",
+        "social",
+    ] {
+        let rowids = tx
+            .prepare(&format!("SELECT rowid FROM {} WHERE bookid = ?1", table))?
+            .query_map([old_id], |row| row.get::<_, i64>(0))?
+            .map(|rowid| rowid.map(|rowid| rowid.to_string()))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if rowids.is_empty() {
+            continue;
+        }
+
+        // Journaled as deletion and insertion, so undo restores the old rows.
+        let condition = format!("bookid = {}", old_id);
+        journal::record_rows(
+            tx,
+            run_id,
+            Kind::Delete,
+            BookRef::Column("bookid"),
+            table,
+            &condition,
+        )?;
         tx.execute(
-            r#"DELETE FROM books_uids WHERE book_id NOT IN ( SELECT id FROM books_impl )"#,
-            [],
-        )
-        .unwrap();
+            &format!(
+                "UPDATE OR IGNORE {} SET bookid = ?1 WHERE {}",
+                table, condition
+            ),
+            [new_id],
+        )?;
+        let condition = format!("bookid = {} AND rowid IN ({})", new_id, rowids.join(", "));
+        journal::record_rows(
+            tx,
+            run_id,
+            Kind::Insert,
+            BookRef::Column("bookid"),
+            table,
+            &condition,
+        )?;
+
+        let left: usize = tx.query_row(
+            &format!("SELECT COUNT(*) FROM {} WHERE bookid = ?1", table),
+            [old_id],
+            |r| r.get(0),
+        )?;
+        if left > 0 {
+            conflicts.push((table, left));
+        }
     }
 
-    tx.execute(
-        r#"DELETE FROM bookshelfs_books WHERE bookid NOT IN ( SELECT id FROM books_impl )"#,
-        [],
-    )
-    .unwrap();
-    tx.execute(
-        r#"DELETE FROM booktogenre WHERE bookid NOT IN ( SELECT id FROM books_impl )"#,
-        [],
-    )
-    .unwrap();
-    tx.execute(
-        r#"DELETE FROM social WHERE bookid NOT IN ( SELECT id FROM books_impl )"#,
-        [],
-    )
-    .unwrap();
+    journal::move_subjects(tx, old_id, new_id)?;
+
+    Ok(conflicts)
+}
+
+/// Journals and deletes all rows of `table` whose `book_column` references
+/// one of the books in `book_list` (comma separated ids).
+fn remove_orphans(
+    tx: &Transaction,
+    run_id: i64,
+    table: &str,
+    book_column: &str,
+    book_list: &str,
+) -> Result<usize> {
+    let condition = format!("{} IN ({})", book_column, book_list);
+    journal::record_rows(
+        tx,
+        run_id,
+        Kind::Delete,
+        BookRef::Column(book_column),
+        table,
+        &condition,
+    )?;
+
+    Ok(tx.execute(&format!("DELETE FROM {} WHERE {}", table, condition), [])?)
+}
+
+/// Removes the given ghost books together with their settings, hashes,
+/// bookshelf entries, genres, social data and recorded subjects.
+fn remove_ghost_books_from_db(
+    tx: &Transaction,
+    profile: &SchemaProfile,
+    run_id: i64,
+    book_ids: &[i32],
+) -> Result<usize> {
+    let book_list = book_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let num = remove_orphans(tx, run_id, "books_impl", "id", &book_list)?;
+
+    remove_orphans(tx, run_id, "books_settings", "bookid", &book_list)?;
+
+    remove_orphans(tx, run_id, profile.hash_table, "book_id", &book_list)?;
+
+    remove_orphans(tx, run_id, "bookshelfs_books", "bookid", &book_list)?;
+    remove_orphans(tx, run_id, "booktogenre", "bookid", &book_list)?;
+    remove_orphans(tx, run_id, "social", "bookid", &book_list)?;
+    journal::forget_subjects(tx, book_ids)?;
+
+    Ok(num)
+}
+
+/// A single planned or applied modification of a book's database entry.
+#[derive(Clone)]
+pub struct Change {
+    pub book_id: i32,
+    pub book: String,
+    pub column: &'static str,
+    pub old_value: String,
+    pub new_value: String,
+}
 
-    num
+/// A book whose content is encrypted by a DRM scheme. Its metadata has
+/// still been fixed from the clear-text package document.
+pub struct ProtectedBook {
+    pub book_id: i32,
+    pub book: String,
+    pub scheme: String,
+}
+
+/// Rows of a moved book which have not been moved to its new entry, since
+/// that one already had its own (e.g. a reading position). They are removed
+/// together with the old entry, but can be restored by undoing the run.
+pub struct RelinkConflict {
+    pub book_id: i32,
+    pub book: String,
+    pub new_book_id: i32,
+    pub table: &'static str,
+    pub rows: usize,
+}
+
+/// A book that has been skipped because it could not be read.
+pub struct FailedBook {
+    pub book_id: i32,
+    pub book: String,
+    pub reason: String,
 }
 
 pub struct Statistics {
+    pub titles_fixed: usize,
     pub authors_fixed: i32,
     pub ghost_books_cleaned: usize,
+    pub ghost_books_quarantined: usize,
+    pub books_relinked: usize,
     pub genres_fixed: usize,
     pub sorting_fixed: usize,
     pub series_fixed: usize,
+    pub series_indices_fixed: usize,
+    pub changes: Vec<Change>,
+    pub failed_books: Vec<FailedBook>,
+    pub protected_books: Vec<ProtectedBook>,
+    pub relink_conflicts: Vec<RelinkConflict>,
+    /// Removable storages whose books have been skipped because they are
+    /// not mounted.
+    pub unmounted_storages: Vec<&'static str>,
+    pub backup_file: Option<PathBuf>,
+    pub run_id: i64,
 }
 
 impl Statistics {
     pub fn anything_fixed(&self) -> bool {
-        &self.authors_fixed > &0
-            || &self.genres_fixed > &0
-            || &self.ghost_books_cleaned > &0
-            || &self.sorting_fixed > &0
-            || &self.series_fixed > &0
+        self.titles_fixed > 0
+            || self.authors_fixed > 0
+            || self.genres_fixed > 0
+            || self.ghost_books_cleaned > 0
+            || self.books_relinked > 0
+            || self.sorting_fixed > 0
+            || self.series_fixed > 0
+            || self.series_indices_fixed > 0
+    }
+
+    fn record(
+        &mut self,
+        entry: &BookEntry,
+        column: &'static str,
+        old_value: &str,
+        new_value: &str,
+    ) {
+        self.changes.push(Change {
+            book_id: entry.id,
+            book: entry.filepath.clone(),
+            column,
+            old_value: old_value.to_string(),
+            new_value: new_value.to_string(),
+        });
+    }
+}
+
+/// Fixes the database entries. If `options.dry_run` is set, all fixes are
+/// applied to an in-memory copy of the database, which is discarded
+/// afterwards, so only the recorded changes remain.
+/// Books that cannot be read are skipped and reported in the statistics,
+/// whereas database errors abort the run without committing anything.
+pub fn fix_db_entries(db_file: &Path, options: &FixOptions) -> Result<Statistics> {
+    // Dry runs work on in-memory copies of the database and the journal, so
+    // nothing is written next to the database and read-only copies can be
+    // checked as well.
+    let mut conn = if options.dry_run {
+        let mut conn = Connection::open_in_memory()?;
+        backup::copy_into_memory(db_file, &mut conn, DatabaseName::Main)?;
+        journal::attach_copy(&mut conn, db_file)?;
+        conn
+    } else {
+        open_existing(db_file)?
+    };
+    conn.pragma_update(None, "foreign_keys", 0)?;
+
+    let profile = SchemaProfile::detect(&conn)?;
+
+    let mut backup_file = None;
+    if !options.dry_run {
+        backup_file = Some(backup::create_backup(&conn, db_file, options.backups)?);
+        journal::attach(&conn, db_file)?;
     }
+
+    let tx = conn.transaction()?;
+    let mut stat = fix_entries(&tx, &profile, options)?;
+    stat.backup_file = backup_file;
+
+    if options.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    Ok(stat)
 }
 
-pub fn fix_db_entries() -> Statistics {
+/// Applies the selected fixers within `tx`, which needs the journal to be
+/// attached.
+fn fix_entries(
+    tx: &Transaction,
+    profile: &SchemaProfile,
+    options: &FixOptions,
+) -> Result<Statistics> {
     let mut stat = Statistics {
+        titles_fixed: 0,
         authors_fixed: 0,
         ghost_books_cleaned: 0,
+        ghost_books_quarantined: 0,
+        books_relinked: 0,
         genres_fixed: 0,
         sorting_fixed: 0,
         series_fixed: 0,
+        series_indices_fixed: 0,
+        changes: Vec::new(),
+        failed_books: Vec::new(),
+        protected_books: Vec::new(),
+        relink_conflicts: Vec::new(),
+        unmounted_storages: Vec::new(),
+        backup_file: None,
+        run_id: 0,
     };
 
-    let mut conn = Connection::open(DATABASE_FILE).unwrap();
-    conn.pragma_update(None, "foreign_keys", &0).unwrap();
-    let tx = conn.transaction().unwrap();
+    let run_id = journal::start_run(tx)?;
+    stat.run_id = run_id;
 
-    let book_entries = get_epubs_from_database(&tx);
+    stat.unmounted_storages = get_unmounted_storages(tx, &options.path_map)?;
+    let storage_ids = LOCAL_STORAGES
+        .iter()
+        .filter(|(_, prefix, _)| !stat.unmounted_storages.contains(prefix))
+        .map(|(storage_id, _, _)| *storage_id)
+        .collect::<Vec<_>>();
+
+    // Relinking and removing ghosts don't need to read the book files.
+    let (book_entries, failed_books) = if options.fixers.iter().any(Fixer::reads_books) {
+        get_books_from_database(tx, profile, &options.path_map, &storage_ids)?
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    stat.failed_books = failed_books;
 
     for entry in book_entries {
-        if let Some(epub_metadata) = epub::get_epub_metadata(&entry.filepath) {
-            // Fix firstauthor…
-            let mut firstauthors = epub_metadata
-                .authors
-                .iter()
-                .filter(|aut| aut.firstauthor.len() > 0)
-                .map(|aut| aut.firstauthor.clone())
-                .collect::<Vec<_>>();
-            firstauthors.sort();
-            if !firstauthors.iter().all(|s| entry.firstauthor.contains(s)) {
-                let mut stmt = tx
-                    .prepare("UPDATE books_impl SET firstauthor = :file_as WHERE id = :book_id")
-                    .unwrap();
-                stmt.execute(
-                    named_params![":file_as": firstauthors.join(" & "), ":book_id": entry.id],
-                )
-                .unwrap();
-                stat.authors_fixed = stat.authors_fixed + 1;
+        let metadata = match format::get_metadata(&entry.filepath) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                stat.failed_books.push(FailedBook {
+                    book_id: entry.id,
+                    book: entry.filepath.clone(),
+                    reason: err.to_string(),
+                });
+                continue;
             }
+        };
+        if let Some(format::Protection::Drm(scheme)) = &metadata.protection {
+            stat.protected_books.push(ProtectedBook {
+                book_id: entry.id,
+                book: entry.filepath.clone(),
+                scheme: scheme.clone(),
+            });
+        }
+        // Without any metadata, the fixers could only clear the entry.
+        if metadata.is_empty() {
+            continue;
+        }
 
-            // Fix first_author_letter
-            let first_author_letter = firstauthors
-                .join(" & ")
+        // Fix title…
+        let mut title = metadata.title.clone();
+        if options.series_suffix && !title.is_empty() {
+            title.push_str(&metadata.series.title_suffix().unwrap_or_default());
+        }
+        let sort_title = if metadata.title_sort.is_empty() {
+            &metadata.title
+        } else {
+            &metadata.title_sort
+        };
+        let mut title_columns = Vec::new();
+        if !title.is_empty() && entry.title != title {
+            title_columns.push(("title", &entry.title, title.clone()));
+        }
+        // Without sort title in the book, the sort title is derived from the
+        // title, but only if that is corrected anyway.
+        if !sort_title.is_empty()
+            && entry.sort_title != *sort_title
+            && (!metadata.title_sort.is_empty() || !title_columns.is_empty())
+        {
+            title_columns.push(("sort_title", &entry.sort_title, sort_title.clone()));
+        }
+        if !title_columns.is_empty() {
+            let first_title_letter = sort_title
                 .chars()
                 .next()
                 .unwrap_or_default()
-                .to_string()
-                .to_uppercase();
-
-            if first_author_letter != "\0" && (entry.first_author_letter != first_author_letter) {
-                let mut stmt = tx
-                        .prepare("UPDATE books_impl SET first_author_letter = :first_letter WHERE id = :book_id")
-                        .unwrap();
-                stmt.execute(
-                    named_params![":first_letter": first_author_letter,":book_id": entry.id],
-                )
-                .unwrap();
-                stat.sorting_fixed = stat.sorting_fixed + 1;
+                .to_uppercase()
+                .to_string();
+            if first_title_letter != "\0" && entry.first_title_letter != first_title_letter {
+                title_columns.push((
+                    "first_title_letter",
+                    &entry.first_title_letter,
+                    first_title_letter,
+                ));
             }
-
-            // Fix author names…
-            let authornames = epub_metadata
-                .authors
-                .iter()
-                .map(|aut| aut.name.clone())
-                .collect::<Vec<_>>();
-            if !authornames.iter().all(|s| entry.author.contains(s))
-                || authornames.join(", ").len() != entry.author.len()
-            {
-                let mut stmt = tx
-                    .prepare("UPDATE books_impl SET author = :authors WHERE id = :book_id")
-                    .unwrap();
-                stmt.execute(
-                    named_params![":authors": authornames.join(", "), ":book_id": entry.id],
-                )
-                .unwrap();
-                stat.authors_fixed = stat.authors_fixed + 1;
+        }
+        if options.fixers.contains(&Fixer::Titles) && !title_columns.is_empty() {
+            for (column, old_value, new_value) in title_columns {
+                journal::record_update(tx, run_id, entry.id, column, &new_value)?;
+                tx.execute(
+                    &format!(
+                        "UPDATE books_impl SET {} = :value WHERE id = :book_id",
+                        column
+                    ),
+                    named_params![":value": new_value, ":book_id": entry.id],
+                )?;
+                stat.record(&entry, column, old_value, &new_value);
             }
+            stat.titles_fixed += 1;
+        }
 
-            // Fix genre…
-            if entry.genre.is_empty() && epub_metadata.genre.len() > 0 {
-                let mut stmt = tx
-                    .prepare(r#"INSERT INTO genres (name) SELECT :genre ON CONFLICT DO NOTHING"#)
-                    .unwrap();
-                stmt.execute(named_params![":genre": &epub_metadata.genre])
-                    .unwrap();
+        // Fix firstauthor…
+        let authors = metadata.main_authors();
+        let firstauthors = authors
+            .iter()
+            .filter(|aut| !aut.firstauthor.is_empty())
+            .map(|aut| aut.firstauthor.clone())
+            .collect::<Vec<_>>();
+        if options.fixers.contains(&Fixer::Authors)
+            && !authors.is_empty()
+            && !firstauthors.iter().all(|s| entry.firstauthor.contains(s))
+        {
+            journal::record_update(
+                tx,
+                run_id,
+                entry.id,
+                "firstauthor",
+                &firstauthors.join(" & "),
+            )?;
+            let mut stmt =
+                tx.prepare("UPDATE books_impl SET firstauthor = :file_as WHERE id = :book_id")?;
+            stmt.execute(
+                named_params![":file_as": firstauthors.join(" & "), ":book_id": entry.id],
+            )?;
+            stat.authors_fixed += 1;
+            stat.record(
+                &entry,
+                "firstauthor",
+                &entry.firstauthor,
+                &firstauthors.join(" & "),
+            );
+        }
+
+        // Fix first_author_letter
+        let first_author_letter = firstauthors
+            .join(" & ")
+            .chars()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+            .to_uppercase();
+
+        if options.fixers.contains(&Fixer::Sorting)
+            && first_author_letter != "\0"
+            && (entry.first_author_letter != first_author_letter)
+        {
+            journal::record_update(
+                tx,
+                run_id,
+                entry.id,
+                "first_author_letter",
+                &first_author_letter,
+            )?;
+            let mut stmt = tx.prepare(
+                "UPDATE books_impl SET first_author_letter = :first_letter WHERE id = :book_id",
+            )?;
+            stmt.execute(named_params![":first_letter": first_author_letter,":book_id": entry.id])?;
+            stat.sorting_fixed += 1;
+            stat.record(
+                &entry,
+                "first_author_letter",
+                &entry.first_author_letter,
+                &first_author_letter,
+            );
+        }
+
+        // Fix author names…
+        let authornames = authors
+            .iter()
+            .map(|aut| aut.name.clone())
+            .collect::<Vec<_>>();
+        if options.fixers.contains(&Fixer::Authors)
+            && !authors.is_empty()
+            && (!authornames.iter().all(|s| entry.author.contains(s))
+                || authornames.join(", ").len() != entry.author.len())
+        {
+            journal::record_update(tx, run_id, entry.id, "author", &authornames.join(", "))?;
+            let mut stmt =
+                tx.prepare("UPDATE books_impl SET author = :authors WHERE id = :book_id")?;
+            stmt.execute(named_params![":authors": authornames.join(", "), ":book_id": entry.id])?;
+            stat.authors_fixed += 1;
+            stat.record(&entry, "author", &entry.author, &authornames.join(", "));
+        }
+
+        // Fix genres…
+        let missing_genres = metadata
+            .genres
+            .iter()
+            .filter(|genre| !entry.genres.contains(genre))
+            .collect::<Vec<_>>();
+        let known_subjects = match options.genre_policy {
+            GenrePolicy::Sync => journal::known_subjects(tx, entry.id)?,
+            _ => Vec::new(),
+        };
+        let stale_genres = entry
+            .genres
+            .iter()
+            .filter(|genre| !metadata.genres.contains(genre))
+            .filter(|genre| match options.genre_policy {
+                GenrePolicy::Add => false,
+                GenrePolicy::Sync => known_subjects.contains(genre),
+                GenrePolicy::Replace => true,
+            })
+            .collect::<Vec<_>>();
+        if options.fixers.contains(&Fixer::Genres) {
+            journal::record_subjects(tx, entry.id, &metadata.genres)?;
+        }
+        if options.fixers.contains(&Fixer::Genres)
+            && (!missing_genres.is_empty() || !stale_genres.is_empty())
+        {
+            for genre in &stale_genres {
+                let genre_id: i64 =
+                    tx.query_row("SELECT id FROM genres WHERE name = ?1", [genre], |r| {
+                        r.get(0)
+                    })?;
+                let condition = format!("bookid = {} AND genreid = {}", entry.id, genre_id);
+                journal::record_rows(
+                    tx,
+                    run_id,
+                    Kind::Delete,
+                    BookRef::Column("bookid"),
+                    "booktogenre",
+                    &condition,
+                )?;
+                tx.execute(&format!("DELETE FROM booktogenre WHERE {}", condition), [])?;
+            }
+            for genre in &missing_genres {
                 let mut stmt = tx
-                    .prepare(
-                        r#"
+                    .prepare(r#"INSERT INTO genres (name) SELECT :genre ON CONFLICT DO NOTHING"#)?;
+                if stmt.execute(named_params![":genre": genre])? > 0 {
+                    journal::record_rows(
+                        tx,
+                        run_id,
+                        Kind::Insert,
+                        BookRef::Id(entry.id),
+                        "genres",
+                        &format!("rowid = {}", tx.last_insert_rowid()),
+                    )?;
+                }
+                let mut stmt = tx.prepare(
+                    r#"
                     INSERT INTO booktogenre (bookid, genreid)
                       VALUES (:bookid,
                         (SELECT id FROM genres WHERE name = :genre)
                       )
                       ON CONFLICT DO NOTHING"#,
-                    )
-                    .unwrap();
-                stmt.execute(named_params![":bookid": &entry.id, ":genre": &epub_metadata.genre])
-                    .unwrap();
-                stat.genres_fixed = stat.genres_fixed + 1;
+                )?;
+                if stmt.execute(named_params![":bookid": &entry.id, ":genre": genre])? > 0 {
+                    journal::record_rows(
+                        tx,
+                        run_id,
+                        Kind::Insert,
+                        BookRef::Column("bookid"),
+                        "booktogenre",
+                        &format!("rowid = {}", tx.last_insert_rowid()),
+                    )?;
+                }
             }
+            let mut genres = entry
+                .genres
+                .iter()
+                .filter(|genre| !stale_genres.contains(genre))
+                .cloned()
+                .collect::<Vec<_>>();
+            genres.extend(missing_genres.into_iter().cloned());
+            stat.genres_fixed += 1;
+            stat.record(
+                &entry,
+                "genre",
+                &entry.genres.join(", "),
+                &genres.join(", "),
+            );
+        }
 
-            // Fix series…
-            if !epub_metadata.series.name.is_empty() && entry.series.is_empty() {
-                let mut stmt = tx
-                    .prepare("UPDATE books_impl SET series = :series, numinseries = :series_index WHERE id = :book_id")
-                    .unwrap();
-                stmt.execute(
-                        named_params![":series": &epub_metadata.series.name, ":series_index": &epub_metadata.series.index, ":book_id": entry.id],
-                    )
-                    .unwrap();
-                stat.series_fixed = stat.series_fixed + 1;
+        // Fix series…
+        if options.fixers.contains(&Fixer::Series)
+            && !metadata.series.name.is_empty()
+            && entry.series != metadata.series.name
+        {
+            journal::record_update(tx, run_id, entry.id, "series", &metadata.series.name)?;
+            let mut stmt =
+                tx.prepare("UPDATE books_impl SET series = :series WHERE id = :book_id")?;
+            stmt.execute(named_params![":series": &metadata.series.name, ":book_id": entry.id])?;
+            stat.series_fixed += 1;
+            stat.record(&entry, "series", &entry.series, &metadata.series.name);
+        }
+
+        // Fix series index (only if the book gives one)…
+        match metadata.series.numinseries() {
+            Some(numinseries)
+                if options.fixers.contains(&Fixer::Series)
+                    && !metadata.series.name.is_empty()
+                    && entry.numinseries != numinseries =>
+            {
+                journal::record_update(tx, run_id, entry.id, "numinseries", &numinseries)?;
+                let mut stmt = tx.prepare(
+                    "UPDATE books_impl SET numinseries = :series_index WHERE id = :book_id",
+                )?;
+                stmt.execute(named_params![":series_index": numinseries, ":book_id": entry.id])?;
+                stat.series_indices_fixed += 1;
+                stat.record(
+                    &entry,
+                    "numinseries",
+                    &entry.numinseries.to_string(),
+                    &numinseries.to_string(),
+                );
+            }
+            _ => (),
+        }
+    }
+
+    // moved or renamed books: the old entries are removed right away, since
+    // their data has been moved to the new ones (only if all storages are
+    // available, like ghost books below)
+    if options.fixers.contains(&Fixer::Relink) && stat.unmounted_storages.is_empty() {
+        let mut relinked = Vec::new();
+        for (old_id, title, new_id) in get_moved_books_from_db(tx, profile)? {
+            for (table, rows) in relink_book(tx, run_id, old_id, new_id)? {
+                stat.relink_conflicts.push(RelinkConflict {
+                    book_id: old_id,
+                    book: title.clone(),
+                    new_book_id: new_id,
+                    table,
+                    rows,
+                });
             }
+            relinked.push(old_id);
+            stat.changes.push(Change {
+                book_id: old_id,
+                book: title,
+                column: "books_impl",
+                old_value: "present".to_string(),
+                new_value: format!("relinked to book {}", new_id),
+            });
+        }
+
+        if !relinked.is_empty() {
+            remove_ghost_books_from_db(tx, profile, run_id, &relinked)?;
+            journal::release(tx, &relinked)?;
+            stat.books_relinked = relinked.len();
         }
     }
 
-    // ghost books
-    let num = remove_ghost_books_from_db(&tx);
-    stat.ghost_books_cleaned = num;
+    // ghost books (only if all storages are available, since the books of an
+    // ejected SD card could otherwise be taken for ghosts)
+    if options.fixers.contains(&Fixer::Ghosts) && stat.unmounted_storages.is_empty() {
+        let ghost_books = get_ghost_books_from_db(tx)?;
+        let book_ids = ghost_books.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let runs = journal::quarantine(tx, &book_ids)?;
+
+        let mut purged = Vec::new();
+        for ((book_id, title), runs) in ghost_books.into_iter().zip(runs) {
+            let new_value = if runs >= options.ghost_runs {
+                purged.push(book_id);
+                "removed".to_string()
+            } else {
+                stat.ghost_books_quarantined += 1;
+                format!(
+                    "quarantined (missing {} of {} runs)",
+                    runs, options.ghost_runs
+                )
+            };
+            stat.changes.push(Change {
+                book_id,
+                book: title,
+                column: "books_impl",
+                old_value: "present".to_string(),
+                new_value,
+            });
+        }
+
+        if !purged.is_empty() {
+            stat.ghost_books_cleaned = remove_ghost_books_from_db(tx, profile, run_id, &purged)?;
+            journal::release(tx, &purged)?;
+        }
+    }
+
+    Ok(stat)
+}
+
+/// Reads the book entries of the database without modifying anything.
+pub fn read_book_entries(
+    db_file: &Path,
+    path_map: &PathMap,
+) -> Result<(Vec<BookEntry>, Vec<FailedBook>)> {
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let profile = SchemaProfile::detect(&conn)?;
+
+    let storage_ids = LOCAL_STORAGES
+        .iter()
+        .map(|(storage_id, _, _)| *storage_id)
+        .collect::<Vec<_>>();
+
+    get_books_from_database(&conn, &profile, path_map, &storage_ids)
+}
 
-    tx.commit().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{database, temp_dir, write_epub};
 
-    stat
+    fn count(tx: &Transaction, table: &str, condition: &str) -> usize {
+        tx.query_row(
+            &format!("SELECT COUNT(*) FROM {} WHERE {}", table, condition),
+            [],
+            |r| r.get(0),
+        )
+        .unwrap()
+    }
+
+    fn query<T: rusqlite::types::FromSql>(tx: &Transaction, sql: &str) -> Vec<T> {
+        tx.prepare(sql)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    /// Stores book 1 as EPUB with the given metadata in `dir`, which is
+    /// mapped to the internal storage by `options`. Its `books_impl` row is
+    /// up to the test.
+    fn add_book(tx: &Transaction, dir: &Path, metadata: &str) {
+        write_epub(&dir.join("book.epub"), metadata);
+        tx.execute_batch(
+            r#"
+            INSERT INTO folders (id, storageid, name) VALUES (1, 1, '/mnt/ext1');
+            INSERT INTO files (book_id, folder_id, filename, storageid, ext)
+              VALUES (1, 1, 'book.epub', 1, 'epub');"#,
+        )
+        .unwrap();
+    }
+
+    fn options(dir: &Path, fixers: &[Fixer]) -> FixOptions {
+        FixOptions {
+            fixers: fixers.to_vec(),
+            dry_run: false,
+            path_map: PathMap::new(Some(dir.to_path_buf()), None),
+            ghost_runs: DEFAULT_GHOST_RUNS,
+            genre_policy: GenrePolicy::Add,
+            series_suffix: false,
+            backups: backup::DEFAULT_BACKUPS,
+        }
+    }
+
+    #[test]
+    fn reconciles_genres_according_to_the_policy() {
+        let dir = temp_dir("genres");
+        for (genre_policy, expected) in [
+            (
+                GenrePolicy::Add,
+                &["Drama", "Favourites", "Old Subject"][..],
+            ),
+            (GenrePolicy::Sync, &["Drama", "Favourites"]),
+            (GenrePolicy::Replace, &["Drama"]),
+        ] {
+            let mut conn = database();
+            let profile = SchemaProfile::detect(&conn).unwrap();
+            let tx = conn.transaction().unwrap();
+            add_book(
+                &tx,
+                &dir,
+                "<dc:title>Play</dc:title><dc:subject>Drama</dc:subject>",
+            );
+            // a genre added on the device and a subject of an earlier version
+            tx.execute_batch(
+                r#"
+                INSERT INTO books_impl (id, title) VALUES (1, 'Play');
+                INSERT INTO genres (id, name) VALUES (1, 'Favourites'), (2, 'Old Subject');
+                INSERT INTO booktogenre (bookid, genreid) VALUES (1, 1), (1, 2);
+                INSERT INTO journal.subjects (book_id, name) VALUES (1, 'Old Subject');"#,
+            )
+            .unwrap();
+            let genres = |tx: &Transaction| {
+                query::<String>(
+                    tx,
+                    r#"
+                    SELECT name FROM genres JOIN booktogenre ON id = genreid
+                      WHERE bookid = 1 ORDER BY name"#,
+                )
+            };
+
+            let options = FixOptions {
+                genre_policy,
+                ..options(&dir, &[Fixer::Genres])
+            };
+            let stat = fix_entries(&tx, &profile, &options).unwrap();
+            assert_eq!(genres(&tx), expected, "{:?}", genre_policy);
+            assert_eq!(
+                query::<String>(&tx, "SELECT name FROM journal.subjects"),
+                ["Drama"]
+            );
+
+            journal::revert_run(&tx, stat.run_id).unwrap();
+            assert_eq!(genres(&tx), ["Favourites", "Old Subject"]);
+            assert_eq!(count(&tx, "genres", "name = 'Drama'"), 0);
+        }
+    }
+
+    #[test]
+    fn rewrites_the_title_columns() {
+        let dir = temp_dir("titles");
+        let hobbit = r#"<dc:title>The Hobbit</dc:title>
+            <meta name="calibre:title_sort" content="Hobbit, The"/>"#;
+        for (metadata, entry, expected, columns) in [
+            // corrected title, the sort title follows
+            (
+                "<dc:title>Right</dc:title>",
+                ("Wrong", "Wrong", "W"),
+                ("Right", "Right", "R"),
+                &["title", "sort_title", "first_title_letter"][..],
+            ),
+            // sort title given by the book
+            (
+                hobbit,
+                ("The Hobbit", "The Hobbit", "T"),
+                ("The Hobbit", "Hobbit, The", "H"),
+                &["sort_title", "first_title_letter"],
+            ),
+            // sort title set on the device for a correct title
+            (
+                "<dc:title>The Hobbit</dc:title>",
+                ("The Hobbit", "Hobbit", "H"),
+                ("The Hobbit", "Hobbit", "H"),
+                &[],
+            ),
+            // no title in the book
+            (
+                "<dc:creator>Ann Author</dc:creator>",
+                ("Kept", "Kept", "K"),
+                ("Kept", "Kept", "K"),
+                &[],
+            ),
+        ] {
+            let mut conn = database();
+            let profile = SchemaProfile::detect(&conn).unwrap();
+            let tx = conn.transaction().unwrap();
+            add_book(&tx, &dir, metadata);
+            tx.execute(
+                r#"
+                INSERT INTO books_impl (id, title, sort_title, first_title_letter)
+                  VALUES (1, ?1, ?2, ?3)"#,
+                [entry.0, entry.1, entry.2],
+            )
+            .unwrap();
+
+            let stat = fix_entries(&tx, &profile, &options(&dir, &[Fixer::Titles])).unwrap();
+            assert_eq!(
+                query::<String>(
+                    &tx,
+                    "SELECT title || ', ' || sort_title || ', ' || first_title_letter FROM books_impl"
+                ),
+                [format!("{}, {}, {}", expected.0, expected.1, expected.2)]
+            );
+            assert_eq!(
+                stat.changes.iter().map(|c| c.column).collect::<Vec<_>>(),
+                columns
+            );
+        }
+    }
+
+    #[test]
+    fn fixes_series_and_indices() {
+        let dir = temp_dir("series");
+        let saga = |index: &str| {
+            format!(
+                r#"<dc:title>Tale</dc:title>
+                <meta name="calibre:series" content="Saga"/>{}"#,
+                index
+            )
+        };
+        for (index, series_suffix, expected) in [
+            (
+                r#"<meta name="calibre:series_index" content="2.5"/>"#,
+                false,
+                "Tale, Saga, 2",
+            ),
+            (
+                r#"<meta name="calibre:series_index" content="2.5"/>"#,
+                true,
+                "Tale [Saga 2.5], Saga, 2",
+            ),
+            (
+                r#"<meta name="calibre:series_index" content="3"/>"#,
+                true,
+                "Tale, Saga, 3",
+            ),
+            // the stored index is kept if the book gives none
+            ("", true, "Tale, Saga, 7"),
+        ] {
+            let mut conn = database();
+            let profile = SchemaProfile::detect(&conn).unwrap();
+            let tx = conn.transaction().unwrap();
+            add_book(&tx, &dir, &saga(index));
+            tx.execute_batch(
+                r#"
+                INSERT INTO books_impl (id, title, sort_title, first_title_letter, series, numinseries)
+                  VALUES (1, 'Tale', 'Tale', 'T', 'Old Saga', 7);"#,
+            )
+            .unwrap();
+
+            let options = FixOptions {
+                series_suffix,
+                ..options(&dir, &[Fixer::Titles, Fixer::Series])
+            };
+            fix_entries(&tx, &profile, &options).unwrap();
+            assert_eq!(
+                query::<String>(
+                    &tx,
+                    "SELECT title || ', ' || series || ', ' || numinseries FROM books_impl"
+                ),
+                [expected]
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_ghost_books_while_the_sd_card_is_unmounted() {
+        let dir = temp_dir("unmounted");
+        let mut conn = database();
+        let profile = SchemaProfile::detect(&conn).unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch(
+            r#"
+            INSERT INTO folders (id, storageid, name) VALUES (2, 2, '/mnt/ext2/Books');
+            INSERT INTO books_impl (id, title) VALUES (1, 'Lost');"#,
+        )
+        .unwrap();
+        let options = |sdcard_root: PathBuf| FixOptions {
+            path_map: PathMap::new(Some(dir.clone()), Some(sdcard_root)),
+            ghost_runs: 1,
+            ..options(&dir, &[Fixer::Relink, Fixer::Ghosts])
+        };
+
+        let stat = fix_entries(&tx, &profile, &options(dir.join("missing"))).unwrap();
+        assert_eq!(stat.unmounted_storages, ["/mnt/ext2"]);
+        assert_eq!(stat.ghost_books_cleaned, 0);
+        assert_eq!(count(&tx, "books_impl", "id = 1"), 1);
+        assert_eq!(count(&tx, "journal.quarantine", "1"), 0);
+
+        let stat = fix_entries(&tx, &profile, &options(dir.clone())).unwrap();
+        assert!(stat.unmounted_storages.is_empty());
+        assert_eq!(stat.ghost_books_cleaned, 1);
+        assert_eq!(count(&tx, "books_impl", "id = 1"), 0);
+    }
+
+    #[test]
+    fn removes_ghost_books_with_their_rows() {
+        let mut conn = database();
+        let profile = SchemaProfile::detect(&conn).unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch(
+            r#"
+            INSERT INTO books_impl (id, title) VALUES (1, 'Ghost'), (2, 'Present');
+            INSERT INTO files (book_id, folder_id, filename) VALUES (2, 1, 'present.epub');
+            INSERT INTO books_settings (bookid, profileid) VALUES (1, 1), (2, 1);
+            INSERT INTO books_fast_hashes (book_id, fast_hash) VALUES (1, 'a'), (2, 'b');
+            INSERT INTO bookshelfs_books (bookshelfid, bookid) VALUES (1, 1);
+            INSERT INTO booktogenre (bookid, genreid) VALUES (1, 1), (2, 1);
+            INSERT INTO social (bookid, type) VALUES (1, 1);
+            INSERT INTO journal.subjects (book_id, name) VALUES (1, 'Drama'), (2, 'Poetry');"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_ghost_books_from_db(&tx).unwrap(),
+            [(1, "Ghost".to_string())]
+        );
+
+        let run_id = journal::start_run(&tx).unwrap();
+        assert_eq!(
+            remove_ghost_books_from_db(&tx, &profile, run_id, &[1]).unwrap(),
+            1
+        );
+        let tables = [
+            ("books_impl", "id"),
+            ("books_settings", "bookid"),
+            ("books_fast_hashes", "book_id"),
+            ("bookshelfs_books", "bookid"),
+            ("booktogenre", "bookid"),
+            ("social", "bookid"),
+        ];
+        for (table, column) in tables {
+            assert_eq!(
+                count(&tx, table, &format!("{} = 1", column)),
+                0,
+                "{}",
+                table
+            );
+        }
+        assert_eq!(count(&tx, "books_impl", "id = 2"), 1);
+        assert_eq!(count(&tx, "booktogenre", "bookid = 2"), 1);
+        assert_eq!(
+            query::<i32>(&tx, "SELECT book_id FROM journal.subjects"),
+            [2]
+        );
+
+        assert_eq!(journal::revert_run(&tx, run_id).unwrap(), 6);
+        for (table, column) in tables {
+            assert_eq!(
+                count(&tx, table, &format!("{} = 1", column)),
+                1,
+                "{}",
+                table
+            );
+        }
+    }
+
+    #[test]
+    fn relinks_moved_books_and_reports_conflicts() {
+        let mut conn = database();
+        let profile = SchemaProfile::detect(&conn).unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch(
+            r#"
+            INSERT INTO books_impl (id, title, author, size) VALUES
+              (1, 'Moved', 'Ann Author', 1000),
+              (2, 'Moved', 'Ann Author', 1000);
+            INSERT INTO files (book_id, folder_id, filename) VALUES (2, 1, 'moved.epub');
+            INSERT INTO books_settings (bookid, profileid, cpage) VALUES
+              (1, 1, 42), (1, 2, 7), (2, 1, 1);
+            INSERT INTO bookshelfs_books (bookshelfid, bookid) VALUES (1, 1);
+            INSERT INTO journal.subjects (book_id, name) VALUES (1, 'Drama');"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_moved_books_from_db(&tx, &profile).unwrap(),
+            [(1, "Moved".to_string(), 2)]
+        );
+
+        let run_id = journal::start_run(&tx).unwrap();
+        assert_eq!(
+            relink_book(&tx, run_id, 1, 2).unwrap(),
+            [("books_settings", 1)]
+        );
+        // the settings of the new book are kept
+        assert_eq!(count(&tx, "books_settings", "bookid = 2 AND cpage = 1"), 1);
+        assert_eq!(count(&tx, "books_settings", "bookid = 2 AND cpage = 7"), 1);
+        assert_eq!(count(&tx, "books_settings", "bookid = 1 AND cpage = 42"), 1);
+        assert_eq!(count(&tx, "bookshelfs_books", "bookid = 2"), 1);
+        assert_eq!(
+            query::<i32>(&tx, "SELECT book_id FROM journal.subjects"),
+            [2]
+        );
+
+        journal::revert_run(&tx, run_id).unwrap();
+        assert_eq!(count(&tx, "books_settings", "bookid = 1"), 2);
+        assert_eq!(count(&tx, "books_settings", "bookid = 2"), 1);
+        assert_eq!(count(&tx, "bookshelfs_books", "bookid = 1"), 1);
+        assert_eq!(count(&tx, "bookshelfs_books", "bookid = 2"), 0);
+    }
 }
@@ -0,0 +1,197 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Mount point of the internal storage on the device.
+pub const INTERNAL_PREFIX: &str = "/mnt/ext1";
+/// Mount point of the SD card on the device.
+pub const SDCARD_PREFIX: &str = "/mnt/ext2";
+
+/// Local storages as stored in `files.storageid`/`folders.storageid`:
+/// (storage id, mount point, removable)
+pub const LOCAL_STORAGES: &[(i32, &str, bool)] =
+    &[(1, INTERNAL_PREFIX, false), (2, SDCARD_PREFIX, true)];
+
+/// Location of the explorer database relative to the internal storage.
+const DATABASE_SUBPATH: &str = "system/explorer-3/explorer-3.db";
+
+/// Directories below which mounted readers are looked for.
+const MOUNT_POINTS: &[&str] = &["/media", "/run/media", "/mnt", "/Volumes"];
+
+/// Maps the paths stored in the database (as seen by the device) to local
+/// paths, e.g. when the reader is mounted via USB on a desktop.
+pub struct PathMap {
+    mappings: Vec<(&'static str, PathBuf)>,
+}
+
+impl PathMap {
+    /// Paths are used as they are, i.e. when running on the device.
+    pub fn identity() -> Self {
+        PathMap {
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Maps the internal storage to `internal_root` and the SD card to
+    /// `sdcard_root`.
+    pub fn new(internal_root: Option<PathBuf>, sdcard_root: Option<PathBuf>) -> Self {
+        let mut mappings = Vec::new();
+        if let Some(root) = internal_root {
+            mappings.push((INTERNAL_PREFIX, root));
+        }
+        if let Some(root) = sdcard_root {
+            mappings.push((SDCARD_PREFIX, root));
+        }
+        PathMap { mappings }
+    }
+
+    /// Checks whether the storage mounted at `prefix` on the device is
+    /// currently available.
+    pub fn is_mounted(&self, prefix: &str) -> bool {
+        if let Some((_, root)) = self.mappings.iter().find(|(p, _)| *p == prefix) {
+            return root.is_dir();
+        }
+
+        match fs::read_to_string("/proc/mounts") {
+            Ok(mounts) => is_mount_point(&mounts, prefix),
+            Err(_) => fs::read_dir(prefix)
+                .map(|mut entries| entries.next().is_some())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Translates a device path into the corresponding local path.
+    pub fn to_local(&self, device_path: &str) -> String {
+        for (prefix, root) in &self.mappings {
+            if let Some(rest) = device_path.strip_prefix(prefix) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    return format!("{}{}", root.to_string_lossy().trim_end_matches('/'), rest);
+                }
+            }
+        }
+        device_path.to_string()
+    }
+}
+
+/// Checks whether `prefix` is listed as mount point in `mounts`, which has
+/// the format of /proc/mounts.
+fn is_mount_point(mounts: &str, prefix: &str) -> bool {
+    mounts
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(prefix))
+}
+
+/// Returns the path of the explorer database of a reader mounted at `root`.
+pub fn database_file(root: &Path) -> PathBuf {
+    root.join(DATABASE_SUBPATH)
+}
+
+/// Looks for mounted readers, i.e. directories up to two levels below the
+/// common mount points which contain an explorer database.
+pub fn discover() -> Vec<PathBuf> {
+    discover_below(MOUNT_POINTS)
+}
+
+/// Looks for mounted readers below the given mount points.
+fn discover_below<P: AsRef<Path>>(mount_points: &[P]) -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+
+    for mount_point in mount_points {
+        let entries = match fs::read_dir(mount_point) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for dir in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if database_file(&dir).is_file() {
+                roots.push(dir);
+                continue;
+            }
+            // e.g. /media/<user>/<label>
+            if let Ok(subentries) = fs::read_dir(&dir) {
+                for subdir in subentries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                    if database_file(&subdir).is_file() {
+                        roots.push(subdir);
+                    }
+                }
+            }
+        }
+    }
+
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn maps_device_paths_to_local_paths() {
+        let path_map = PathMap::new(
+            Some(PathBuf::from("/media/reader/")),
+            Some(PathBuf::from("/media/sdcard")),
+        );
+
+        assert_eq!(
+            path_map.to_local("/mnt/ext1/Books/a.epub"),
+            "/media/reader/Books/a.epub"
+        );
+        assert_eq!(
+            path_map.to_local("/mnt/ext2/Books/b.epub"),
+            "/media/sdcard/Books/b.epub"
+        );
+        assert_eq!(path_map.to_local("/mnt/ext1"), "/media/reader");
+        assert_eq!(path_map.to_local("/mnt/ext10/c.epub"), "/mnt/ext10/c.epub");
+        assert_eq!(
+            PathMap::new(None, None).to_local("/mnt/ext2/Books/b.epub"),
+            "/mnt/ext2/Books/b.epub"
+        );
+        assert_eq!(
+            PathMap::identity().to_local("/mnt/ext1/Books/a.epub"),
+            "/mnt/ext1/Books/a.epub"
+        );
+    }
+
+    #[test]
+    fn checks_mapped_storages() {
+        let dir = temp_dir("mounted");
+        let path_map = PathMap::new(Some(dir.clone()), Some(dir.join("missing")));
+
+        assert!(path_map.is_mounted(INTERNAL_PREFIX));
+        assert!(!path_map.is_mounted(SDCARD_PREFIX));
+    }
+
+    #[test]
+    fn finds_mount_points_in_the_mount_table() {
+        let mounts = "\
+/dev/mmcblk0p1 /mnt/ext1 vfat rw,relatime 0 0
+/dev/mmcblk1p1 /mnt/ext2/books vfat rw,relatime 0 0
+tmpfs /tmp tmpfs rw 0 0
+";
+
+        assert!(is_mount_point(mounts, INTERNAL_PREFIX));
+        assert!(!is_mount_point(mounts, SDCARD_PREFIX));
+        assert!(!is_mount_point("", INTERNAL_PREFIX));
+    }
+
+    #[test]
+    fn discovers_mounted_readers() {
+        let dir = temp_dir("discover");
+        for root in ["reader", "user/sdcard", "user/reader", "usb"] {
+            fs::create_dir_all(dir.join(root)).unwrap();
+        }
+        for root in ["reader", "user/reader"] {
+            let db_file = database_file(&dir.join(root));
+            fs::create_dir_all(db_file.parent().unwrap()).unwrap();
+            fs::write(db_file, "").unwrap();
+        }
+
+        assert_eq!(
+            discover_below(&[&dir, &dir.join("missing")]),
+            [dir.join("reader"), dir.join("user/reader")]
+        );
+    }
+}
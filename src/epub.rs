@@ -1,316 +1,1083 @@
 use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::Read,
+    fs,
+    io::{Read, Seek},
 };
 
 use quick_xml::{events::Event, Reader};
 use zip::ZipArchive;
 
-#[derive(Debug)]
-pub struct Author {
-    pub name: String,
-    pub firstauthor: String,
-}
+use crate::{
+    error::{Error, Result},
+    format::{Author, BookMetadata, Collection, Date, Identifier, Meta, Protection, Series, Title},
+    xml::{decode, decode_text, DC_NAMESPACE},
+};
 
-#[derive(Debug)]
-pub struct Series {
-    pub name: String,
-    pub index: i32,
-}
+const CONTAINER_FILE: &str = "META-INF/container.xml";
+const PACKAGE_MEDIA_TYPE: &[u8] = b"application/oebps-package+xml";
 
-impl Series {
-    fn new() -> Self {
-        Series {
-            name: String::new(),
-            index: 0,
+/// Decodes `%XX` escapes as used in the paths of container.xml.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
         }
     }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
-#[derive(Debug)]
-pub struct EpubMetadata {
-    pub authors: Vec<Author>,
-    pub genre: String,
-    pub series: Series,
+/// Returns the name of the archive entry matching `name`, ignoring the case
+/// if there is no exact match.
+fn find_entry<R: Read + Seek>(archive: &ZipArchive<R>, name: &str) -> Option<String> {
+    let name = name.trim_start_matches('/');
+    archive
+        .file_names()
+        .find(|entry| *entry == name)
+        .or_else(|| {
+            archive
+                .file_names()
+                .find(|entry| entry.eq_ignore_ascii_case(name))
+        })
+        .map(|entry| entry.to_string())
 }
 
-impl EpubMetadata {
-    fn new() -> Self {
-        EpubMetadata {
-            authors: Vec::new(),
-            genre: String::new(),
-            series: Series::new(),
+/// Returns the `full-path`s of the rootfiles listed in container.xml, the
+/// OPF renditions first.
+fn read_rootfiles(container: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(container);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut rootfiles = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) if e.local_name() == b"rootfile" => {
+                let attributes = e.attributes().flatten().collect::<Vec<_>>();
+                if let Some(full_path) = attributes.iter().find(|attr| attr.key == b"full-path") {
+                    let is_package = attributes.iter().any(|attr| {
+                        attr.key == b"media-type" && &*attr.value == PACKAGE_MEDIA_TYPE
+                    });
+                    let path = percent_decode(&full_path.unescape_and_decode_value(&reader)?);
+                    rootfiles.push((!is_package, path));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(err) => return Err(err.into()),
+            _ => (),
         }
+        buf.clear();
     }
+
+    rootfiles.sort_by_key(|(is_other, _)| *is_other);
+    Ok(rootfiles.into_iter().map(|(_, path)| path).collect())
 }
 
-fn get_rootfile(archive: &mut ZipArchive<File>) -> String {
-    let mut container = archive.by_name("META-INF/container.xml").unwrap();
-    let mut xml_str_buffer = String::new();
+/// Returns the name of the archive entry holding the package document (OPF).
+/// If container.xml is missing or points nowhere, the archive is searched
+/// for an `.opf` file.
+fn get_rootfile<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String> {
+    let mut rootfiles = Vec::new();
+    if let Some(container_file) = find_entry(archive, CONTAINER_FILE) {
+        let mut container = String::new();
+        archive
+            .by_name(&container_file)?
+            .read_to_string(&mut container)?;
+        rootfiles = read_rootfiles(&container)?;
+    }
+
+    if let Some(entry) = rootfiles.iter().find_map(|path| find_entry(archive, path)) {
+        return Ok(entry);
+    }
+
+    let mut opf_files = archive
+        .file_names()
+        .filter(|entry| entry.to_lowercase().ends_with(".opf"))
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>();
+    opf_files.sort();
+    match (rootfiles.first(), opf_files.len()) {
+        (_, 1) => Ok(opf_files.remove(0)),
+        (Some(path), _) => Err(Error::Metadata(format!(
+            "package document {} not found in the archive",
+            path
+        ))),
+        (None, 0) => Err(Error::Metadata("no package document found".to_string())),
+        (None, _) => Err(Error::Metadata(format!(
+            "several package documents found, but none is referenced by {}",
+            CONTAINER_FILE
+        ))),
+    }
+}
+
+const ENCRYPTION_FILE: &str = "META-INF/encryption.xml";
+const RIGHTS_FILE: &str = "META-INF/rights.xml";
+const LCP_LICENSE_FILE: &str = "META-INF/license.lcpl";
+const FAIRPLAY_FILE: &str = "META-INF/sinf.xml";
 
-    container.read_to_string(&mut xml_str_buffer).unwrap();
+/// Algorithms that only obfuscate embedded fonts.
+const FONT_OBFUSCATION_ALGORITHMS: &[&[u8]] = &[
+    b"http://www.idpf.org/2008/embedding",
+    b"http://ns.adobe.com/pdf/enc#RC",
+];
 
-    let mut reader = Reader::from_str(&xml_str_buffer);
+/// An entry listed in encryption.xml.
+struct EncryptedEntry {
+    path: String,
+    algorithm: Vec<u8>,
+}
+
+fn read_encrypted_entries(encryption: &str) -> Result<Vec<EncryptedEntry>> {
+    let mut reader = Reader::from_str(encryption);
     reader.trim_text(true);
 
     let mut buf = Vec::new();
-    let mut opf_filename = String::new();
+    let mut entries = Vec::new();
+    let mut algorithm = Vec::new();
 
     loop {
         match reader.read_event(&mut buf) {
-            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) if e.local_name() == b"rootfile" => {
-                opf_filename = String::from_utf8(
-                    e.attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key == b"full-path")
-                        .next()
-                        .unwrap()
-                        .unwrap()
-                        .value
-                        .to_vec(),
-                )
-                .unwrap();
-                break;
-            }
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => match e.local_name() {
+                b"EncryptedData" => algorithm.clear(),
+                b"EncryptionMethod" => {
+                    if let Some(attr) = e.attributes().flatten().find(|a| a.key == b"Algorithm") {
+                        algorithm = attr.value.into_owned();
+                    }
+                }
+                b"CipherReference" => {
+                    if let Some(attr) = e.attributes().flatten().find(|a| a.key == b"URI") {
+                        entries.push(EncryptedEntry {
+                            path: percent_decode(&attr.unescape_and_decode_value(&reader)?),
+                            algorithm: algorithm.clone(),
+                        });
+                    }
+                }
+                _ => (),
+            },
             Ok(Event::Eof) => break,
+            Err(err) => return Err(err.into()),
             _ => (),
         }
+        buf.clear();
     }
-    opf_filename
+
+    Ok(entries)
 }
 
-pub fn get_epub_metadata(filename: &str) -> Option<EpubMetadata> {
-    let mut epub_meta = EpubMetadata::new();
-    let file = fs::File::open(&filename);
+/// Names the DRM scheme from the license files in the archive.
+fn drm_scheme<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String> {
+    if find_entry(archive, LCP_LICENSE_FILE).is_some() {
+        return Ok("Readium LCP".to_string());
+    }
+    if find_entry(archive, FAIRPLAY_FILE).is_some() {
+        return Ok("Apple FairPlay".to_string());
+    }
+    if let Some(rights_file) = find_entry(archive, RIGHTS_FILE) {
+        let mut rights = String::new();
+        archive.by_name(&rights_file)?.read_to_string(&mut rights)?;
+        if rights.contains("http://ns.adobe.com/adept") {
+            return Ok("Adobe ADEPT".to_string());
+        }
+    }
+    Ok("unknown".to_string())
+}
 
-    let file = match file {
-        Err(_) => return None,
-        Ok(file) => file,
-    };
+/// Detects DRM and font obfuscation. Fails if the package document itself
+/// is encrypted, since then no metadata can be read.
+fn get_protection<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    opf_filename: &str,
+) -> Result<Option<Protection>> {
+    let mut entries = Vec::new();
+    if let Some(encryption_file) = find_entry(archive, ENCRYPTION_FILE) {
+        let mut encryption = String::new();
+        archive
+            .by_name(&encryption_file)?
+            .read_to_string(&mut encryption)?;
+        entries = read_encrypted_entries(&encryption)?;
+    }
 
-    let mut archive = ZipArchive::new(file).unwrap();
+    let is_drm = entries
+        .iter()
+        .any(|entry| !FONT_OBFUSCATION_ALGORITHMS.contains(&entry.algorithm.as_slice()))
+        || find_entry(archive, RIGHTS_FILE).is_some();
+    if !is_drm {
+        return Ok(if entries.is_empty() {
+            None
+        } else {
+            Some(Protection::FontObfuscation)
+        });
+    }
 
-    let opf_filename = get_rootfile(&mut archive);
+    let protection = Protection::Drm(drm_scheme(archive)?);
+    if entries.iter().any(|entry| {
+        entry
+            .path
+            .trim_start_matches('/')
+            .eq_ignore_ascii_case(opf_filename)
+    }) {
+        return Err(Error::Metadata(format!(
+            "package document is encrypted ({})",
+            protection
+        )));
+    }
+    Ok(Some(protection))
+}
 
-    let mut xml_str_buffer = String::new();
-    let mut opf = archive.by_name(&opf_filename).unwrap();
-    opf.read_to_string(&mut xml_str_buffer).unwrap();
+const OPF_NAMESPACE: &[u8] = b"http://www.idpf.org/2007/opf";
+const CALIBRE_NAMESPACE: &[u8] = b"http://calibre.kovidgoyal.net/2009/metadata";
 
-    let mut reader = Reader::from_str(&xml_str_buffer);
-    let mut buf = Vec::new();
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Namespace {
+    /// Unprefixed attributes don't belong to any namespace.
+    None,
+    Dc,
+    Opf,
+    Calibre,
+    Other,
+}
 
-    let mut curr_id = String::new();
-    let mut creator_found = false;
-    let mut file_as_found = false;
-    let mut role_found = false;
-    let mut genre_found = false;
-    let mut series_found = false;
-    let mut series_index_found = false;
-    let mut is_epub3 = false;
-
-    #[derive(Debug)]
-    struct XmlAut {
-        name: String,
-        sort: String,
-        role: String,
-    }
-
-    impl XmlAut {
-        fn new() -> Self {
-            XmlAut {
-                name: String::new(),
-                sort: String::new(),
-                role: String::new(),
-            }
+impl Namespace {
+    fn from_uri(uri: Option<&[u8]>) -> Self {
+        match uri {
+            None => Namespace::None,
+            Some(DC_NAMESPACE) => Namespace::Dc,
+            Some(OPF_NAMESPACE) => Namespace::Opf,
+            Some(CALIBRE_NAMESPACE) => Namespace::Calibre,
+            Some(_) => Namespace::Other,
+        }
+    }
+}
+
+/// An element of the OPF metadata with resolved namespaces and unescaped,
+/// trimmed text and attribute values.
+struct MetaElement {
+    namespace: Namespace,
+    name: String,
+    attributes: Vec<(Namespace, String, String)>,
+    /// Resolved value of the `name` attribute of EPUB 2 `<meta>` elements.
+    meta_name: Option<(Namespace, String)>,
+    text: String,
+}
+
+impl MetaElement {
+    fn attribute(&self, namespace: Namespace, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(ns, n, _)| *ns == namespace && n == name)
+            .map(|(_, _, value)| value.as_str())
+    }
+
+    /// The `id` as referenced by the `refines` attribute of EPUB 3 metadata.
+    fn refinable_id(&self) -> String {
+        self.attribute(Namespace::None, "id")
+            .map(|id| format!("#{}", id))
+            .unwrap_or_default()
+    }
+
+    fn is(&self, namespace: Namespace, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+}
+
+/// Resolves the prefix of a qualified name in an attribute value (like
+/// `calibre:series`). Calibre doesn't declare its prefix, so the well-known
+/// prefix is accepted as well.
+fn resolve_value<B: std::io::BufRead>(
+    reader: &Reader<B>,
+    value: &str,
+    ns_buf: &[u8],
+) -> (Namespace, String) {
+    match value.split_once(':') {
+        Some(("calibre", name)) => (Namespace::Calibre, name.to_string()),
+        Some((_, name)) => {
+            let (uri, _) = reader.event_namespace(value.as_bytes(), ns_buf);
+            (Namespace::from_uri(uri), name.to_string())
+        }
+        None => (Namespace::None, value.to_string()),
+    }
+}
+
+/// Splits a qualified name whose prefix couldn't be resolved into namespace
+/// and local name. OPF files without default namespace or with undeclared
+/// prefixes are common, so unprefixed names get the namespace `unprefixed`
+/// and the well-known prefixes are accepted, as in `resolve_value`.
+fn guess_namespace(qname: &[u8], unprefixed: Namespace) -> (Namespace, &[u8]) {
+    match qname.iter().position(|byte| *byte == b':') {
+        Some(colon) => {
+            let namespace = match &qname[..colon] {
+                b"dc" => Namespace::Dc,
+                b"opf" => Namespace::Opf,
+                b"calibre" => Namespace::Calibre,
+                _ => Namespace::Other,
+            };
+            (namespace, &qname[colon + 1..])
         }
+        None => (unprefixed, qname),
     }
+}
 
-    let mut xml_authors = HashMap::new();
+/// Resolves the namespace of an element, see `guess_namespace`.
+fn element_namespace(uri: Option<&[u8]>, qname: &[u8]) -> Namespace {
+    match uri {
+        Some(_) => Namespace::from_uri(uri),
+        None => guess_namespace(qname, Namespace::Opf).0,
+    }
+}
+
+/// The parts of a package document the metadata is taken from.
+struct Package {
+    version: String,
+    /// The elements inside `<metadata>` (including those nested in the
+    /// legacy `<dc-metadata>` and `<x-metadata>`).
+    metadata: Vec<MetaElement>,
+    /// The `<item>` elements of the manifest.
+    manifest: Vec<MetaElement>,
+}
+
+fn read_package(xml: &str) -> Result<Package> {
+    let mut reader = Reader::from_str(xml);
+    let mut buf = Vec::new();
+    let mut ns_buf = Vec::new();
+
+    let mut version = String::new();
+    let mut elements = Vec::new();
+    let mut manifest = Vec::new();
+    let mut in_metadata = false;
+    let mut current: Option<MetaElement> = None;
 
     loop {
-        match reader.read_event(&mut buf) {
-            // See if we have EPUB3 or EPUB2
-            Ok(Event::Start(ref e)) if e.local_name() == b"package" => {
-                if e.attributes().any(|attr| {
-                    attr.as_ref().unwrap().key == b"version"
-                        && attr.as_ref().unwrap().value.starts_with(b"3")
-                }) {
-                    is_epub3 = true;
-                }
-            }
-            Ok(Event::Start(ref e)) if e.local_name() == b"creator" => {
-                creator_found = true;
-                if is_epub3 {
-                    if let Some(idval) = e
-                        .attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key == b"id")
-                        .next()
-                    {
-                        curr_id = "#".to_string()
-                            + String::from_utf8(idval.unwrap().value.to_vec())
-                                .unwrap()
-                                .as_str();
-                        xml_authors.insert(curr_id.clone(), XmlAut::new());
-                    } else {
-                        curr_id = "none".to_string() + xml_authors.len().to_string().as_str();
-                        let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                        entry.role = "aut".to_string();
-                    }
-                } else {
-                    if let Some(file_as_val) = e
-                        .attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key.ends_with(b"file-as"))
-                        .next()
-                    {
-                        curr_id = "none".to_string() + xml_authors.len().to_string().as_str();
-                        let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                        entry.sort = file_as_val
-                            .unwrap()
-                            .unescape_and_decode_value(&reader)
-                            .unwrap_or_default();
-                        entry.role = "aut".to_string();
-                    } else if let Some(_role_val) = e
-                        .attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key.ends_with(b"role"))
-                        .next()
-                    {
-                        curr_id = "none".to_string() + xml_authors.len().to_string().as_str();
+        let (namespace, event) = reader.read_namespaced_event(&mut buf, &mut ns_buf)?;
+
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let namespace = element_namespace(namespace, e.name());
+                let name = decode(e.local_name());
+                let mut attributes = Vec::new();
+                let mut meta_name = None;
+                for attr in e.attributes().flatten() {
+                    let (attr_ns, attr_name) = match reader.attribute_namespace(attr.key, &ns_buf) {
+                        (None, qname) => guess_namespace(qname, Namespace::None),
+                        (uri, name) => (Namespace::from_uri(uri), name),
+                    };
+                    let attr_name = decode(attr_name);
+                    let value = decode_text(&attr.value).trim().to_string();
+                    if attr_ns == Namespace::None && attr_name == "name" {
+                        meta_name = Some(resolve_value(&reader, &value, &ns_buf));
                     }
+                    attributes.push((attr_ns, attr_name, value));
                 }
-            }
-            Ok(Event::Text(ref e)) if creator_found => {
-                if is_epub3 {
-                    let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                    entry.name = String::from_utf8(e.to_vec()).unwrap();
-                } else {
-                    let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                    entry.name = String::from_utf8(e.to_vec()).unwrap();
-                    entry.role = "aut".to_string();
-                }
+                let element = MetaElement {
+                    namespace,
+                    name,
+                    attributes,
+                    meta_name,
+                    text: String::new(),
+                };
 
-                creator_found = false;
-            }
-            Ok(Event::Start(ref e)) if e.local_name() == b"meta" && is_epub3 => {
-                if let Some(refines) = e
-                    .attributes()
-                    .filter(|attr| attr.as_ref().unwrap().key == b"refines")
-                    .next()
+                if element.is(Namespace::Opf, "package") {
+                    version = element
+                        .attribute(Namespace::None, "version")
+                        .unwrap_or_default()
+                        .to_string();
+                } else if element.is(Namespace::Opf, "item") && !in_metadata {
+                    manifest.push(element);
+                } else if element.is(Namespace::Opf, "metadata") {
+                    in_metadata = matches!(event, Event::Start(_));
+                } else if in_metadata
+                    && current.is_none()
+                    && !element.is(Namespace::Opf, "dc-metadata")
+                    && !element.is(Namespace::Opf, "x-metadata")
                 {
-                    if e.attributes().any(|attr| {
-                        attr.as_ref().unwrap().key == b"property"
-                            && attr.as_ref().unwrap().value.ends_with(b"file-as")
-                    }) {
-                        curr_id = String::from_utf8(refines.unwrap().value.to_vec()).unwrap();
-                        file_as_found = true;
-                    } else if e.attributes().any(|attr| {
-                        attr.as_ref().unwrap().key == b"property"
-                            && attr.as_ref().unwrap().value.ends_with(b"role")
-                    }) {
-                        curr_id = String::from_utf8(refines.unwrap().value.to_vec()).unwrap();
-                        role_found = true;
-                    } else if e.attributes().any(|attr| {
-                        attr.as_ref().unwrap().key == b"property"
-                            && attr.as_ref().unwrap().value.ends_with(b"group-position")
-                    }) {
-                        series_index_found = true;
+                    match event {
+                        Event::Start(_) => current = Some(element),
+                        _ => elements.push(element),
                     }
                 }
-                if e.attributes().any(|attr| {
-                    attr.as_ref().unwrap().key == b"property"
-                        && attr
-                            .as_ref()
-                            .unwrap()
-                            .value
-                            .ends_with(b"belongs-to-collection")
-                }) {
-                    series_found = true;
+            }
+            Event::Text(ref e) => {
+                if let Some(element) = current.as_mut() {
+                    element.text += &decode_text(e.escaped());
                 }
             }
-            Ok(Event::Empty(ref e)) if e.local_name() == b"meta" && !is_epub3 => {
-                if e.attributes().any(|attr| {
-                    attr.as_ref().unwrap().key == b"name"
-                        && attr
-                            .as_ref()
-                            .unwrap()
-                            .unescaped_value()
-                            .unwrap()
-                            .ends_with(b"series")
-                }) {
-                    epub_meta.series.name = e
-                        .attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key == b"content")
-                        .next()
-                        .unwrap()
-                        .unwrap()
-                        .unescape_and_decode_value(&reader)
-                        .unwrap_or_default();
-                } else if e.attributes().any(|attr| {
-                    attr.as_ref().unwrap().key == b"name"
-                        && attr
-                            .as_ref()
-                            .unwrap()
-                            .unescaped_value()
-                            .unwrap()
-                            .ends_with(b"series_index")
-                }) {
-                    let index_float = e
-                        .attributes()
-                        .filter(|attr| attr.as_ref().unwrap().key == b"content")
-                        .next()
-                        .unwrap()
-                        .unwrap()
-                        .unescape_and_decode_value(&reader)
-                        .unwrap_or_default()
-                        .parse::<f32>()
-                        .unwrap_or_default();
-                    epub_meta.series.index = index_float as i32;
+            Event::CData(ref e) => {
+                if let Some(element) = current.as_mut() {
+                    element.text += &decode(e);
                 }
             }
-            Ok(Event::Text(ref e)) if file_as_found && is_epub3 => {
-                let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                entry.sort = String::from_utf8(e.to_vec()).unwrap();
-
-                file_as_found = false;
+            Event::End(ref e) => {
+                let namespace = element_namespace(namespace, e.name());
+                let name = decode(e.local_name());
+                if namespace == Namespace::Opf && name == "metadata" {
+                    in_metadata = false;
+                } else if let Some(mut element) = current.take() {
+                    if element.namespace == namespace && element.name == name {
+                        element.text = element.text.trim().to_string();
+                        elements.push(element);
+                    } else {
+                        // end of an element nested in the current one
+                        current = Some(element);
+                    }
+                }
             }
-            Ok(Event::Text(ref e)) if role_found && is_epub3 => {
-                let entry = xml_authors.entry(curr_id.clone()).or_insert(XmlAut::new());
-                entry.role = String::from_utf8(e.to_vec()).unwrap();
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(Package {
+        version,
+        metadata: elements,
+        manifest,
+    })
+}
+
+/// Returns the scheme of an identifier given as URN, e.g. `urn:isbn:…`.
+fn identifier_scheme(value: &str) -> String {
+    let value = value.to_lowercase();
+    if value.starts_with("urn:isbn:") {
+        "ISBN".to_string()
+    } else if value.starts_with("urn:uuid:") {
+        "UUID".to_string()
+    } else {
+        String::new()
+    }
+}
+
+/// Sets `field` from an EPUB 3 refinement. The native mechanism of the
+/// package's version takes precedence: refinements override EPUB 2 attributes
+/// in EPUB 3 packages, but only fill in missing values in EPUB 2 packages.
+fn merge(field: &mut String, refinement: String, is_epub3: bool) {
+    if !refinement.is_empty() && (is_epub3 || field.is_empty()) {
+        *field = refinement;
+    }
+}
+
+/// Parses the metadata of an OPF package document.
+fn parse_opf(xml: &str) -> Result<BookMetadata> {
+    let mut epub_meta = BookMetadata::new();
+    let package = read_package(xml)?;
+    let is_epub3 = package.version.starts_with('3');
+
+    // Elements which may be refined by EPUB 3 meta elements, by id.
+    let mut titles = Vec::new();
+    let mut authors = Vec::new();
+    let mut identifiers = Vec::new();
+    let mut collections = Vec::new();
+    let mut display_seqs = Vec::new();
 
-                role_found = false;
+    for element in package
+        .metadata
+        .iter()
+        .filter(|e| e.namespace == Namespace::Dc)
+    {
+        let id = element.refinable_id();
+        // EPUB 2 stores role, file-as, scheme and event as attributes, EPUB 3
+        // in refining meta elements. Both are read, see `merge`.
+        let attribute = |name| {
+            element
+                .attribute(Namespace::Opf, name)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let text = element.text.clone();
+
+        match element.name.as_str() {
+            "title" => titles.push((
+                id,
+                Title {
+                    text,
+                    title_type: String::new(),
+                    display_seq: None,
+                    file_as: String::new(),
+                },
+            )),
+            "creator" | "contributor" => authors.push((
+                id,
+                Author {
+                    name: text,
+                    firstauthor: attribute("file-as"),
+                    role: attribute("role"),
+                    is_creator: element.name == "creator",
+                },
+            )),
+            "subject" if !text.is_empty() && !epub_meta.genres.contains(&text) => {
+                epub_meta.genres.push(text);
+            }
+            "language" if !text.is_empty() => epub_meta.languages.push(text),
+            "identifier" => {
+                let scheme = match attribute("scheme") {
+                    scheme if scheme.is_empty() => identifier_scheme(&text),
+                    scheme => scheme,
+                };
+                identifiers.push((
+                    id,
+                    Identifier {
+                        scheme,
+                        value: text,
+                    },
+                ));
             }
-            Ok(Event::Text(ref e)) if series_found && is_epub3 => {
-                epub_meta.series.name = String::from_utf8(e.to_vec()).unwrap();
+            "publisher" => epub_meta.publisher = text,
+            "date" => epub_meta.dates.push(Date {
+                event: attribute("event"),
+                value: text,
+            }),
+            "description" => epub_meta.description = text,
+            "rights" => epub_meta.rights = text,
+            _ => (),
+        }
+    }
+
+    let mut cover_id = String::new();
+    let mut calibre_series = String::new();
+    let mut calibre_series_index = None;
+
+    for meta in package
+        .metadata
+        .iter()
+        .filter(|e| e.is(Namespace::Opf, "meta"))
+    {
+        let attribute = |name| meta.attribute(Namespace::None, name).unwrap_or_default();
+        let content = attribute("content").to_string();
 
-                series_found = false;
+        // EPUB 2 meta elements (Calibre writes them into EPUB 3 as well)
+        if let Some((namespace, name)) = &meta.meta_name {
+            epub_meta.metas.push(Meta {
+                property: attribute("name").to_string(),
+                refines: String::new(),
+                value: content.clone(),
+            });
+            match (namespace, name.as_str()) {
+                (Namespace::Calibre, "title_sort") => epub_meta.title_sort = content,
+                (Namespace::Calibre, "series") => calibre_series = content,
+                (Namespace::Calibre, "series_index") => {
+                    calibre_series_index = Series::parse_index(&content)
+                }
+                (Namespace::None, "cover") => cover_id = content,
+                _ => (),
             }
-            Ok(Event::Text(ref e)) if series_index_found && is_epub3 => {
-                epub_meta.series.index = String::from_utf8(e.to_vec())
-                    .unwrap()
-                    .parse()
-                    .unwrap_or_default();
+            continue;
+        }
+
+        let property = attribute("property");
+        let refines = attribute("refines");
+        epub_meta.metas.push(Meta {
+            property: property.to_string(),
+            refines: refines.to_string(),
+            value: meta.text.clone(),
+        });
+        let text = meta.text.clone();
+        let title = titles.iter_mut().find(|(id, _)| id == refines);
+        let author = authors.iter_mut().find(|(id, _)| id == refines);
+        let identifier = identifiers.iter_mut().find(|(id, _)| id == refines);
+        let collection = collections.iter_mut().find(|(id, _)| id == refines);
 
-                series_index_found = false;
+        match (property, title, author, identifier, collection) {
+            ("file-as", Some((_, title)), _, _, _) => merge(&mut title.file_as, text, is_epub3),
+            ("file-as", _, Some((_, author)), _, _) => {
+                merge(&mut author.firstauthor, text, is_epub3)
             }
-            Ok(Event::Start(ref e)) if e.local_name() == b"subject" => {
-                genre_found = true;
+            ("role", _, Some((_, author)), _, _) => merge(&mut author.role, text, is_epub3),
+            ("title-type", Some((_, title)), _, _, _) => title.title_type = text,
+            ("display-seq", Some((_, title)), _, _, _) => title.display_seq = text.parse().ok(),
+            ("display-seq", _, Some(_), _, _) => {
+                display_seqs.push((refines.to_string(), text.parse().ok()))
             }
-            Ok(Event::Text(ref e)) if genre_found => {
-                epub_meta.genre = e.unescape_and_decode(&reader).unwrap();
-                genre_found = false;
+            ("identifier-type", _, _, Some((_, identifier)), _) => {
+                // ONIX code list 5: 02 = ISBN-10, 15 = ISBN-13
+                let scheme = match text.as_str() {
+                    "02" | "15" => "ISBN".to_string(),
+                    _ => text,
+                };
+                merge(&mut identifier.scheme, scheme, is_epub3)
+            }
+            ("belongs-to-collection", _, _, _, _) => collections.push((
+                meta.refinable_id(),
+                Collection {
+                    name: text,
+                    collection_type: String::new(),
+                    index: None,
+                },
+            )),
+            ("collection-type", _, _, _, Some((_, collection))) => {
+                collection.collection_type = text
+            }
+            ("group-position", _, _, _, Some((_, collection))) => {
+                collection.index = Series::parse_index(&text)
             }
-            Ok(Event::Eof) => break,
             _ => (),
         }
     }
 
-    epub_meta.authors = xml_authors
+    // The main title wins, otherwise the first one in display order.
+    let mut titles = titles
         .into_iter()
-        .filter(|&(_, ref xml_author)| &xml_author.role == "aut" && &xml_author.name.len() > &0)
-        .map(|(_key, value)| Author {
-            name: value.name,
-            firstauthor: value.sort,
+        .map(|(_, title)| title)
+        .filter(|t| !t.text.is_empty())
+        .collect::<Vec<_>>();
+    titles.sort_by_key(|t| (t.title_type != "main", t.display_seq.unwrap_or(i32::MAX)));
+    if let Some(title) = titles.first() {
+        epub_meta.title = title.text.clone();
+        if !title.file_as.is_empty() {
+            epub_meta.title_sort = title.file_as.clone();
+        }
+    }
+    epub_meta.titles = titles;
+
+    // Creators are listed in document order unless a display order is given.
+    authors.retain(|(_, a)| !a.name.is_empty());
+    authors.sort_by_key(|(id, _)| {
+        display_seqs
+            .iter()
+            .find(|(refines, _)| refines == id)
+            .and_then(|(_, display_seq)| *display_seq)
+            .unwrap_or(i32::MAX)
+    });
+    epub_meta.authors = authors
+        .into_iter()
+        .map(|(_, mut author)| {
+            if author.role.is_empty() {
+                author.role = if author.is_creator { "aut" } else { "ctb" }.to_string();
+            }
+            author
         })
         .collect();
 
-    Some(epub_meta)
+    epub_meta.identifiers = identifiers.into_iter().map(|(_, i)| i).collect();
+
+    // Calibre's series is the native one of EPUB 2; in EPUB 3 it only fills
+    // in for a missing collection or group position.
+    if !calibre_series.is_empty() {
+        match collections
+            .iter_mut()
+            .find(|(_, c)| c.name == calibre_series)
+        {
+            Some((_, collection)) if collection.index.is_none() => {
+                collection.index = calibre_series_index
+            }
+            Some(_) => (),
+            None => collections.insert(
+                if is_epub3 { collections.len() } else { 0 },
+                (
+                    String::new(),
+                    Collection {
+                        name: calibre_series,
+                        collection_type: "series".to_string(),
+                        index: calibre_series_index,
+                    },
+                ),
+            ),
+        }
+    }
+    epub_meta.collections = collections
+        .into_iter()
+        .map(|(_, c)| c)
+        .filter(|c| !c.name.is_empty())
+        .collect();
+    // A "set" is a group of related works, not a series.
+    if let Some(collection) = epub_meta
+        .collections
+        .iter()
+        .find(|c| c.collection_type != "set")
+    {
+        epub_meta.series = Series {
+            name: collection.name.clone(),
+            index: collection.index,
+        };
+    }
+
+    epub_meta.cover = package
+        .manifest
+        .iter()
+        .find(|item| {
+            let attribute = |name| item.attribute(Namespace::None, name).unwrap_or_default();
+            attribute("id") == cover_id
+                || attribute("properties")
+                    .split_whitespace()
+                    .any(|p| p == "cover-image")
+        })
+        .and_then(|item| item.attribute(Namespace::None, "href"))
+        .unwrap_or_default()
+        .to_string();
+
+    Ok(epub_meta)
+}
+
+fn read_epub_metadata<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<BookMetadata> {
+    let opf_filename = get_rootfile(archive)?;
+    let protection = get_protection(archive, &opf_filename)?;
+
+    let mut xml_str_buffer = String::new();
+    archive
+        .by_name(&opf_filename)?
+        .read_to_string(&mut xml_str_buffer)?;
+
+    let mut epub_meta = parse_opf(&xml_str_buffer)?;
+    epub_meta.protection = protection;
+    Ok(epub_meta)
+}
+
+pub fn get_epub_metadata(filename: &str) -> Result<BookMetadata> {
+    let file = fs::File::open(filename)?;
+
+    let mut archive = ZipArchive::new(file)?;
+
+    read_epub_metadata(&mut archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{archive, authors};
+
+    fn container(rootfiles: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+            <container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+              <rootfiles>{}</rootfiles>
+            </container>"#,
+            rootfiles
+        )
+    }
+
+    #[test]
+    fn prefers_the_opf_rendition() {
+        let container = container(
+            r#"<rootfile full-path="book.pdf" media-type="application/pdf"/>
+            <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>"#,
+        );
+        let mut archive = archive(&[
+            (CONTAINER_FILE, &container),
+            ("book.pdf", ""),
+            ("OEBPS/content.opf", ""),
+        ]);
+
+        assert_eq!(get_rootfile(&mut archive).unwrap(), "OEBPS/content.opf");
+    }
+
+    #[test]
+    fn decodes_rootfile_paths_and_ignores_case() {
+        let container = container(
+            r#"<rootfile full-path="OEBPS/My%20Book&amp;Co.opf" media-type="application/oebps-package+xml"/>"#,
+        );
+        let mut archive = archive(&[(CONTAINER_FILE, &container), ("oebps/my book&co.OPF", "")]);
+
+        assert_eq!(get_rootfile(&mut archive).unwrap(), "oebps/my book&co.OPF");
+    }
+
+    #[test]
+    fn searches_the_archive_without_container() {
+        let mut archive = archive(&[
+            ("mimetype", "application/epub+zip"),
+            ("OPS/package.opf", ""),
+        ]);
+
+        assert_eq!(get_rootfile(&mut archive).unwrap(), "OPS/package.opf");
+    }
+
+    #[test]
+    fn reports_unresolvable_rootfiles() {
+        let container = container(
+            r#"<rootfile full-path="missing.opf" media-type="application/oebps-package+xml"/>"#,
+        );
+        let mut archive = archive(&[(CONTAINER_FILE, &container), ("a.opf", ""), ("b.opf", "")]);
+
+        assert_eq!(
+            get_rootfile(&mut archive).unwrap_err().to_string(),
+            "invalid metadata: package document missing.opf not found in the archive"
+        );
+    }
+
+    fn encryption(algorithm: &str, uri: &str) -> String {
+        format!(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+                xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+              <enc:EncryptedData>
+                <enc:EncryptionMethod Algorithm="{}"/>
+                <enc:CipherData><enc:CipherReference URI="{}"/></enc:CipherData>
+              </enc:EncryptedData>
+            </encryption>"#,
+            algorithm, uri
+        )
+    }
+
+    const OPF: &str = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+      <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Locked</dc:title></metadata>
+    </package>"#;
+
+    #[test]
+    fn detects_font_obfuscation() {
+        let encryption = encryption("http://www.idpf.org/2008/embedding", "OEBPS/font.otf");
+        let mut archive = archive(&[(ENCRYPTION_FILE, &encryption), ("content.opf", OPF)]);
+
+        let epub_meta = read_epub_metadata(&mut archive).unwrap();
+        assert_eq!(epub_meta.protection, Some(Protection::FontObfuscation));
+        assert_eq!(epub_meta.title, "Locked");
+    }
+
+    #[test]
+    fn reads_drm_protected_books() {
+        let encryption = encryption(
+            "http://www.w3.org/2001/04/xmlenc#aes128-cbc",
+            "OEBPS/chapter%201.xhtml",
+        );
+        let rights = r#"<adept:rights xmlns:adept="http://ns.adobe.com/adept"/>"#;
+        let mut archive = archive(&[
+            (ENCRYPTION_FILE, &encryption),
+            (RIGHTS_FILE, rights),
+            ("content.opf", OPF),
+        ]);
+
+        let epub_meta = read_epub_metadata(&mut archive).unwrap();
+        assert_eq!(
+            epub_meta.protection,
+            Some(Protection::Drm("Adobe ADEPT".to_string()))
+        );
+        assert_eq!(epub_meta.title, "Locked");
+    }
+
+    #[test]
+    fn reports_encrypted_package_documents() {
+        let encryption = encryption("http://www.w3.org/2001/04/xmlenc#aes256-cbc", "content.opf");
+        let mut archive = archive(&[
+            (ENCRYPTION_FILE, &encryption),
+            (LCP_LICENSE_FILE, "{}"),
+            ("content.opf", "encrypted"),
+        ]);
+
+        assert_eq!(
+            read_epub_metadata(&mut archive).unwrap_err().to_string(),
+            "invalid metadata: package document is encrypted (Readium LCP DRM)"
+        );
+    }
+
+    #[test]
+    fn unescapes_and_trims_text_and_attributes() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/entities.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "The Fox\u{2019}s Tale");
+        assert_eq!(
+            authors(&epub_meta),
+            [
+                ("Simon & Schuster", "Schuster, Simon & Co.", "aut"),
+                ("Flann O'Brien", "O'Brien, Flann", "aut")
+            ]
+        );
+        assert_eq!(epub_meta.genres, ["Crime & Mystery", "Humour"]);
+        assert_eq!(epub_meta.series.name, "Tales & Fables");
+        assert_eq!(epub_meta.series.index, Some(3.0));
+    }
+
+    #[test]
+    fn resolves_namespace_prefixes() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/prefixed.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "Prefixed Title");
+        assert_eq!(
+            authors(&epub_meta),
+            [("Wanda Writer", "Writer, Wanda", "aut")]
+        );
+        assert_eq!(epub_meta.series.name, "Prefixes");
+        assert_eq!(epub_meta.series.index, Some(1.0));
+    }
+
+    #[test]
+    fn ignores_elements_of_other_namespaces() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/impostors.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "Real Title");
+        assert_eq!(authors(&epub_meta), [("Real Author", "", "aut")]);
+        assert!(epub_meta.genres.is_empty());
+        assert_eq!(epub_meta.series.name, "Real Series");
+        assert_eq!(epub_meta.series.index, Some(2.0));
+    }
+
+    #[test]
+    fn reads_legacy_metadata_sections() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/legacy.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "Legacy Title");
+        assert_eq!(authors(&epub_meta), [("Old Author", "Author, Old", "aut")]);
+        assert_eq!(epub_meta.genres, ["History"]);
+        assert_eq!(epub_meta.series.name, "Old Series");
+    }
+
+    #[test]
+    fn falls_back_to_well_known_prefixes() {
+        for opf in [
+            include_str!("../tests/opf/unqualified.opf"),
+            include_str!("../tests/opf/undeclared.opf"),
+        ] {
+            let epub_meta = parse_opf(opf).unwrap();
+
+            assert!(epub_meta.title.ends_with(" Title"));
+            assert_eq!(authors(&epub_meta), [("Una Author", "Author, Una", "aut")]);
+            assert_eq!(epub_meta.genres, ["Drama"]);
+            assert_eq!(epub_meta.series.name, "Plain Series");
+            assert_eq!(epub_meta.series.index, Some(4.0));
+        }
+    }
+
+    fn identifiers(epub_meta: &BookMetadata) -> Vec<(&str, &str)> {
+        epub_meta
+            .identifiers
+            .iter()
+            .map(|i| (i.scheme.as_str(), i.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_complete_epub2_metadata() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/full2.opf")).unwrap();
+
+        assert_eq!(
+            authors(&epub_meta),
+            [
+                ("Ann Author", "Author, Ann", "aut"),
+                ("calibre (5.0.0) [https://calibre-ebook.com]", "", "bkp")
+            ]
+        );
+        assert_eq!(epub_meta.main_authors().len(), 1);
+        assert_eq!(
+            identifiers(&epub_meta),
+            [
+                ("calibre", "42"),
+                ("uuid", "0b7c5a0e-4c0a-4d3d-9a8c-1e2f3a4b5c6d"),
+                ("ISBN", "9780000000002")
+            ]
+        );
+        assert_eq!(epub_meta.languages, ["en", "de"]);
+        assert_eq!(epub_meta.publisher, "Example & Sons");
+        assert_eq!(epub_meta.dates[0].event, "publication");
+        assert_eq!(epub_meta.dates[0].value, "2001-02-03");
+        assert_eq!(epub_meta.description, "<p>A complete description.</p>");
+        assert_eq!(epub_meta.rights, "Public Domain");
+        assert_eq!(epub_meta.cover, "images/cover.jpg");
+        assert_eq!(epub_meta.series.name, "Complete Series");
+        assert_eq!(epub_meta.series.index, Some(4.0));
+        assert_eq!(epub_meta.collections.len(), 1);
+        assert!(epub_meta
+            .metas
+            .iter()
+            .any(|m| m.property == "calibre:timestamp" && m.value == "2020-01-01T00:00:00+00:00"));
+    }
+
+    #[test]
+    fn reads_the_complete_epub3_metadata() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/full3.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "Complete Book");
+        assert_eq!(authors(&epub_meta), [("Ann Author", "", "aut")]);
+        assert_eq!(
+            identifiers(&epub_meta),
+            [
+                ("UUID", "urn:uuid:0b7c5a0e-4c0a-4d3d-9a8c-1e2f3a4b5c6d"),
+                ("ISBN", "9780000000002")
+            ]
+        );
+        assert_eq!(epub_meta.languages, ["en"]);
+        assert_eq!(epub_meta.dates[0].event, "");
+        assert_eq!(epub_meta.cover, "cover.png");
+        assert_eq!(
+            epub_meta
+                .collections
+                .iter()
+                .map(|c| (c.name.as_str(), c.collection_type.as_str(), c.index))
+                .collect::<Vec<_>>(),
+            [
+                ("Complete Series", "series", Some(4.0)),
+                ("Box Set", "set", None)
+            ]
+        );
+        assert!(epub_meta
+            .metas
+            .iter()
+            .any(|m| m.property == "dcterms:modified" && m.value == "2020-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn applies_epub3_refinements() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/epub3.opf")).unwrap();
+
+        assert_eq!(epub_meta.title, "The Main Title");
+        assert_eq!(epub_meta.title_sort, "Main Title, The");
+        assert_eq!(
+            authors(&epub_meta),
+            [
+                ("Al First", "First, Al", "aut"),
+                ("Bea Second", "", "aut"),
+                ("Tess Translator", "", "trl")
+            ]
+        );
+        assert_eq!(epub_meta.series.name, "The <Collection>");
+        assert_eq!(epub_meta.series.index, Some(2.5));
+    }
+
+    #[test]
+    fn prefers_the_group_position_to_calibre_series_index() {
+        let opf = |group_position: &str| {
+            format!(
+                r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+                  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+                    <dc:title>Tale</dc:title>
+                    <meta property="belongs-to-collection" id="c1">Saga</meta>
+                    {}
+                    <meta name="calibre:series" content="Saga"/>
+                    <meta name="calibre:series_index" content="2"/>
+                  </metadata>
+                </package>"#,
+                group_position
+            )
+        };
+
+        let epub_meta = parse_opf(&opf(
+            r##"<meta refines="#c1" property="group-position">3.5</meta>"##,
+        ))
+        .unwrap();
+        assert_eq!(epub_meta.series.name, "Saga");
+        assert_eq!(epub_meta.series.index, Some(3.5));
+
+        let epub_meta = parse_opf(&opf("")).unwrap();
+        assert_eq!(epub_meta.series.index, Some(2.0));
+    }
+
+    #[test]
+    fn merges_epub2_metadata_into_epub3() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/mixed3.opf")).unwrap();
+
+        assert_eq!(
+            authors(&epub_meta),
+            [
+                ("Lou Legacy", "Legacy, Lou", "aut"),
+                ("Will Both", "Both, Will", "aut")
+            ]
+        );
+        assert_eq!(epub_meta.collections.len(), 2);
+        assert_eq!(epub_meta.series.name, "Calibre Saga");
+        assert_eq!(epub_meta.series.index, Some(3.5));
+    }
+
+    #[test]
+    fn prefers_epub2_metadata_in_epub2() {
+        let epub_meta = parse_opf(include_str!("../tests/opf/mixed2.opf")).unwrap();
+
+        assert_eq!(authors(&epub_meta), [("Will Both", "Both, Will", "edt")]);
+        assert_eq!(epub_meta.series.name, "Calibre Saga");
+        assert_eq!(epub_meta.series.index, Some(4.0));
+    }
 }
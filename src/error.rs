@@ -0,0 +1,61 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the explorer database failed.
+    Database(rusqlite::Error),
+    /// A book file could not be opened or read.
+    Io(std::io::Error),
+    /// A book archive is corrupt or lacks a required entry.
+    Archive(zip::result::ZipError),
+    /// An XML document inside a book could not be parsed.
+    Xml(quick_xml::Error),
+    /// The database doesn't look like expected.
+    Schema(String),
+    /// The book doesn't contain the expected metadata.
+    Metadata(String),
+    /// A journaled run doesn't exist or cannot be undone.
+    Journal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Database(err) => write!(f, "database error: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Archive(err) => write!(f, "archive error: {}", err),
+            Error::Xml(err) => write!(f, "XML error: {}", err),
+            Error::Schema(msg) => write!(f, "unexpected database schema: {}", msg),
+            Error::Metadata(msg) => write!(f, "invalid metadata: {}", msg),
+            Error::Journal(msg) => write!(f, "journal: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Database(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(err: zip::result::ZipError) -> Self {
+        Error::Archive(err)
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(err: quick_xml::Error) -> Self {
+        Error::Xml(err)
+    }
+}
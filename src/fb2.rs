@@ -0,0 +1,303 @@
+use std::{fs, io::Read};
+
+use quick_xml::{events::BytesStart, events::Event, Reader};
+use zip::ZipArchive;
+
+use crate::{
+    error::{Error, Result},
+    format::{Author, BookMetadata, Collection, Date, Identifier, Series},
+    xml,
+};
+
+/// Characters 0x80 to 0xBF of windows-1251; 0xC0 to 0xFF are А to я.
+const CP1251_HIGH: &str = "ЂЃ‚ѓ„…†‡€‰Љ‹ЊЌЋЏђ‘’“”•–—\u{fffd}™љ›њќћџ\
+    \u{a0}ЎўЈ¤Ґ¦§Ё©Є«¬\u{ad}®Ї°±Ііґµ¶·ё№є»јЅѕї";
+
+/// Returns the encoding given in the XML declaration, in lower case.
+fn declared_encoding(bytes: &[u8]) -> String {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]);
+    let declaration = head
+        .trim_start_matches('\u{feff}')
+        .strip_prefix("<?xml")
+        .and_then(|declaration| declaration.split("?>").next())
+        .unwrap_or_default();
+    declaration
+        .split("encoding=")
+        .nth(1)
+        .and_then(|value| {
+            let quote = value.chars().next()?;
+            value.get(quote.len_utf8()..)?.split(quote).next()
+        })
+        .unwrap_or("utf-8")
+        .to_lowercase()
+}
+
+/// Decodes the document according to its XML declaration. Besides UTF-8,
+/// the single-byte encodings common in FB2 files are supported.
+fn decode_document(bytes: &[u8]) -> Result<String> {
+    match declared_encoding(bytes).as_str() {
+        "utf-8" | "utf8" => Ok(String::from_utf8_lossy(bytes)
+            .trim_start_matches('\u{feff}')
+            .to_string()),
+        "windows-1251" | "cp1251" => Ok(bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x00..=0x7f => byte as char,
+                0x80..=0xbf => CP1251_HIGH.chars().nth(byte as usize - 0x80).unwrap(),
+                _ => char::from_u32(0x410 + byte as u32 - 0xc0).unwrap(),
+            })
+            .collect()),
+        "iso-8859-1" | "latin1" => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        encoding => Err(Error::Metadata(format!(
+            "unsupported encoding {}",
+            encoding
+        ))),
+    }
+}
+
+/// The name parts of an `author` or `translator` element.
+#[derive(Default)]
+struct Person {
+    first_name: String,
+    middle_name: String,
+    last_name: String,
+    nickname: String,
+}
+
+impl Person {
+    fn into_author(self, role: &str) -> Author {
+        let given_names = [self.first_name, self.middle_name]
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let name = [given_names.as_str(), self.last_name.as_str()]
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let firstauthor = match (self.last_name.is_empty(), given_names.is_empty()) {
+            (true, _) => String::new(),
+            (false, true) => self.last_name,
+            (false, false) => format!("{}, {}", self.last_name, given_names),
+        };
+        Author {
+            name: if name.is_empty() { self.nickname } else { name },
+            firstauthor,
+            role: role.to_string(),
+            is_creator: role == "aut",
+        }
+    }
+}
+
+/// Joins the element names below the root element, e.g.
+/// `description/title-info/author`.
+fn location(path: &[String]) -> String {
+    path.iter().skip(1).cloned().collect::<Vec<_>>().join("/")
+}
+
+fn sequence(reader: &Reader<&[u8]>, e: &BytesStart) -> Result<Collection> {
+    let mut collection = Collection {
+        name: String::new(),
+        collection_type: "series".to_string(),
+        index: None,
+    };
+    for attr in e.attributes().flatten() {
+        let value = attr.unescape_and_decode_value(reader)?;
+        match attr.key {
+            b"name" => collection.name = value.trim().to_string(),
+            b"number" => collection.index = Series::parse_index(&value),
+            _ => (),
+        }
+    }
+    Ok(collection)
+}
+
+/// Reads the `description` of a FictionBook document. The body is not
+/// parsed.
+fn parse_fb2(xml: &str) -> Result<BookMetadata> {
+    let mut book_meta = BookMetadata::new();
+
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    // Names of the open elements, the root element first.
+    let mut path = Vec::new();
+    let mut text = String::new();
+    let mut person = Person::default();
+    let mut has_description = false;
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => {
+                path.push(String::from_utf8_lossy(e.local_name()).into_owned());
+                text.clear();
+                match location(&path).as_str() {
+                    "body" => break,
+                    "description" => has_description = true,
+                    "description/title-info/sequence" => {
+                        book_meta.collections.push(sequence(&reader, e)?)
+                    }
+                    _ => (),
+                }
+            }
+            Ok(Event::Empty(ref e))
+                if e.local_name() == b"sequence" && location(&path) == "description/title-info" =>
+            {
+                book_meta.collections.push(sequence(&reader, e)?);
+            }
+            Ok(Event::Text(ref e)) => text.push_str(&xml::decode_text(e.escaped())),
+            Ok(Event::CData(ref e)) => text.push_str(&String::from_utf8_lossy(e)),
+            Ok(Event::End(_)) => {
+                let value = text.trim().to_string();
+                match location(&path).as_str() {
+                    "description/title-info/book-title" => book_meta.title = value,
+                    "description/title-info/author/first-name"
+                    | "description/title-info/translator/first-name" => person.first_name = value,
+                    "description/title-info/author/middle-name"
+                    | "description/title-info/translator/middle-name" => person.middle_name = value,
+                    "description/title-info/author/last-name"
+                    | "description/title-info/translator/last-name" => person.last_name = value,
+                    "description/title-info/author/nickname"
+                    | "description/title-info/translator/nickname" => person.nickname = value,
+                    "description/title-info/author" => book_meta
+                        .authors
+                        .push(std::mem::take(&mut person).into_author("aut")),
+                    "description/title-info/translator" => book_meta
+                        .authors
+                        .push(std::mem::take(&mut person).into_author("trl")),
+                    "description/title-info/genre"
+                        if !value.is_empty() && !book_meta.genres.contains(&value) =>
+                    {
+                        book_meta.genres.push(value)
+                    }
+                    "description/title-info/lang" if !value.is_empty() => {
+                        book_meta.languages.push(value)
+                    }
+                    "description/publish-info/publisher" => book_meta.publisher = value,
+                    "description/publish-info/year" => book_meta.dates.push(Date {
+                        event: "publication".to_string(),
+                        value,
+                    }),
+                    "description/publish-info/isbn" if !value.is_empty() => {
+                        book_meta.identifiers.push(Identifier {
+                            scheme: "ISBN".to_string(),
+                            value,
+                        })
+                    }
+                    _ => (),
+                }
+                path.pop();
+                text.clear();
+            }
+            Ok(Event::Eof) => break,
+            Err(err) => return Err(err.into()),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    if !has_description {
+        return Err(Error::Metadata(
+            "no FictionBook description found".to_string(),
+        ));
+    }
+
+    book_meta.authors.retain(|author| !author.name.is_empty());
+    book_meta.collections.retain(|c| !c.name.is_empty());
+    if let Some(collection) = book_meta.collections.first() {
+        book_meta.series = Series {
+            name: collection.name.clone(),
+            index: collection.index,
+        };
+    }
+
+    Ok(book_meta)
+}
+
+/// Reads the metadata of an `.fb2` or a zipped `.fb2.zip` file.
+pub fn get_fb2_metadata(filename: &str) -> Result<BookMetadata> {
+    let mut bytes = Vec::new();
+    if filename.to_lowercase().ends_with(".zip") {
+        let mut archive = ZipArchive::new(fs::File::open(filename)?)?;
+        let entry = archive
+            .file_names()
+            .find(|entry| entry.to_lowercase().ends_with(".fb2"))
+            .map(|entry| entry.to_string())
+            .ok_or_else(|| Error::Metadata("no FB2 document found in the archive".to_string()))?;
+        archive.by_name(&entry)?.read_to_end(&mut bytes)?;
+    } else {
+        fs::File::open(filename)?.read_to_end(&mut bytes)?;
+    }
+
+    parse_fb2(&decode_document(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::authors;
+
+    #[test]
+    fn reads_the_title_info() {
+        let book_meta = parse_fb2(include_str!("../tests/fb2/book.fb2")).unwrap();
+
+        assert_eq!(book_meta.title, "Roadside & Picnic");
+        assert_eq!(
+            authors(&book_meta),
+            [
+                (
+                    "Arkady Natanovich Strugatsky",
+                    "Strugatsky, Arkady Natanovich",
+                    "aut"
+                ),
+                ("Anonymous", "", "aut"),
+                ("Olena Bormashenko", "Bormashenko, Olena", "trl")
+            ]
+        );
+        assert_eq!(book_meta.genres, ["sf_fantasy", "adventure"]);
+        assert_eq!(book_meta.languages, ["ru"]);
+        assert_eq!(book_meta.series.name, "Noon Universe");
+        assert_eq!(book_meta.series.index, Some(3.0));
+        assert_eq!(book_meta.collections.len(), 2);
+        assert_eq!(book_meta.publisher, "Chicago Review Press");
+        assert_eq!(book_meta.identifiers[0].value, "978-1-61374-341-6");
+    }
+
+    #[test]
+    fn leaves_a_missing_series_index_unset() {
+        let book_meta = parse_fb2(
+            r#"<FictionBook><description><title-info><sequence name="Noon Universe"/></title-info></description></FictionBook>"#,
+        )
+        .unwrap();
+
+        assert_eq!(book_meta.series.name, "Noon Universe");
+        assert_eq!(book_meta.series.index, None);
+        assert_eq!(book_meta.series.numinseries(), None);
+    }
+
+    #[test]
+    fn decodes_windows_1251() {
+        assert_eq!(CP1251_HIGH.chars().count(), 0x40);
+
+        let mut bytes = br#"<?xml version="1.0" encoding="windows-1251"?><FictionBook><description><title-info><book-title>"#.to_vec();
+        bytes.extend([0xcf, 0xe8, 0xea, 0xed, 0xe8, 0xea, 0x20, 0xab, 0xb8, 0xbb]);
+        bytes.extend(b"</book-title></title-info></description></FictionBook>");
+
+        let book_meta = parse_fb2(&decode_document(&bytes).unwrap()).unwrap();
+        assert_eq!(book_meta.title, "Пикник «ё»");
+    }
+
+    #[test]
+    fn rejects_other_documents() {
+        assert_eq!(
+            decode_document(br#"<?xml version='1.0' encoding='KOI8-R'?>"#)
+                .unwrap_err()
+                .to_string(),
+            "invalid metadata: unsupported encoding koi8-r"
+        );
+        assert!(parse_fb2("<html><body/></html>").is_err());
+        assert!(decode_document("<?xml version='1.0' encoding=ü?>".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt;
+
+use crate::{
+    cbz, epub,
+    error::{Error, Result},
+    fb2, mobi, pdf,
+};
+
+#[derive(Debug)]
+pub struct Title {
+    pub text: String,
+    /// EPUB 3 title type, e.g. `main`, `subtitle` or `collection`.
+    pub title_type: String,
+    pub display_seq: Option<i32>,
+    pub file_as: String,
+}
+
+/// A creator or contributor of the book.
+#[derive(Debug)]
+pub struct Author {
+    pub name: String,
+    /// The name as used for sorting (`file-as`).
+    pub firstauthor: String,
+    /// MARC relator code, e.g. `aut`, `edt`, `trl` or `ill`.
+    pub role: String,
+    /// `dc:creator` rather than `dc:contributor`.
+    pub is_creator: bool,
+}
+
+#[derive(Debug)]
+pub struct Identifier {
+    /// e.g. `ISBN`, `UUID` or `calibre`, empty if unknown.
+    pub scheme: String,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub struct Date {
+    /// EPUB 2 event, e.g. `publication` or `modification`.
+    pub event: String,
+    pub value: String,
+}
+
+/// A collection the book belongs to, e.g. an EPUB 3 collection, a Calibre
+/// series or an FB2 sequence.
+#[derive(Debug)]
+pub struct Collection {
+    pub name: String,
+    /// `series` or `set`, empty if not given.
+    pub collection_type: String,
+    pub index: Option<f64>,
+}
+
+/// A `<meta>` element as found in the package document.
+#[derive(Debug)]
+pub struct Meta {
+    /// EPUB 3 property or EPUB 2 name.
+    pub property: String,
+    pub refines: String,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub struct Series {
+    pub name: String,
+    /// Position within the series, e.g. 2.5 for a novella between the
+    /// second and the third volume. `None` if the book doesn't give one.
+    pub index: Option<f64>,
+}
+
+impl Series {
+    fn new() -> Self {
+        Series {
+            name: String::new(),
+            index: None,
+        }
+    }
+
+    /// Parses a series index as found in the EPUB 2 `calibre:series_index`
+    /// and the EPUB 3 `group-position` metadata. Missing or invalid indices
+    /// yield `None`.
+    pub fn parse_index(value: &str) -> Option<f64> {
+        value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|index| index.is_finite() && *index >= 0.0)
+    }
+
+    /// The index as stored in the integer column `numinseries`: fractional
+    /// indices are rounded down, so 2.5 is sorted right after 2.
+    pub fn numinseries(&self) -> Option<i32> {
+        self.index.map(|index| index.floor() as i32)
+    }
+
+    /// Suffix which can be appended to the title of a book with a
+    /// fractional index, so its position remains visible on the device.
+    pub fn title_suffix(&self) -> Option<String> {
+        match self.index {
+            Some(index) if !self.name.is_empty() && index.fract() != 0.0 => {
+                Some(format!(" [{} {}]", self.name, index))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How the content of a book is protected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protection {
+    /// Only embedded fonts are obfuscated; the content is readable.
+    FontObfuscation,
+    /// The content is encrypted by the given DRM scheme.
+    Drm(String),
+}
+
+impl fmt::Display for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protection::FontObfuscation => write!(f, "font obfuscation"),
+            Protection::Drm(scheme) => write!(f, "{} DRM", scheme),
+        }
+    }
+}
+
+/// The metadata of a book, whatever its format. The fields follow the
+/// package document of EPUB, the most complete of the formats.
+#[derive(Debug)]
+pub struct BookMetadata {
+    /// The main title.
+    pub title: String,
+    pub title_sort: String,
+    pub titles: Vec<Title>,
+    /// All creators and contributors in display order.
+    pub authors: Vec<Author>,
+    /// The subjects.
+    pub genres: Vec<String>,
+    pub languages: Vec<String>,
+    pub identifiers: Vec<Identifier>,
+    pub publisher: String,
+    pub dates: Vec<Date>,
+    pub description: String,
+    pub rights: String,
+    /// The series the book belongs to.
+    pub series: Series,
+    pub collections: Vec<Collection>,
+    /// Path of the cover image relative to the package document.
+    pub cover: String,
+    pub metas: Vec<Meta>,
+    /// How the book is protected, if at all.
+    pub protection: Option<Protection>,
+}
+
+impl BookMetadata {
+    /// Whether the book provides none of the metadata the fixers use, e.g.
+    /// an encrypted PDF without XMP packet.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_empty()
+            && self.authors.is_empty()
+            && self.genres.is_empty()
+            && self.series.name.is_empty()
+    }
+
+    /// The authors of the book, i.e. the creators with role `aut` or, if
+    /// there are none (e.g. anthologies), the editors.
+    pub fn main_authors(&self) -> Vec<&Author> {
+        let authors = self
+            .authors
+            .iter()
+            .filter(|author| author.role == "aut")
+            .collect::<Vec<_>>();
+        if !authors.is_empty() {
+            return authors;
+        }
+        self.authors
+            .iter()
+            .filter(|author| author.role == "edt")
+            .collect()
+    }
+
+    pub fn new() -> Self {
+        BookMetadata {
+            title: String::new(),
+            title_sort: String::new(),
+            titles: Vec::new(),
+            authors: Vec::new(),
+            genres: Vec::new(),
+            languages: Vec::new(),
+            identifiers: Vec::new(),
+            publisher: String::new(),
+            dates: Vec::new(),
+            description: String::new(),
+            rights: String::new(),
+            series: Series::new(),
+            collections: Vec::new(),
+            cover: String::new(),
+            metas: Vec::new(),
+            protection: None,
+        }
+    }
+}
+
+/// Values of the `ext` column of the books the fixers look at. Zipped FB2
+/// files may be listed as `zip`, so the file name decides in the end.
+pub const EXTENSIONS: &[&str] = &[
+    "epub", "fb2", "fb2.zip", "zip", "pdf", "mobi", "azw", "azw3", "cbz",
+];
+
+/// The book formats whose metadata can be read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Epub,
+    Fb2,
+    Pdf,
+    Mobi,
+    Cbz,
+}
+
+impl Format {
+    const SUFFIXES: [(&'static str, Format); 8] = [
+        (".epub", Format::Epub),
+        (".fb2", Format::Fb2),
+        (".fb2.zip", Format::Fb2),
+        (".pdf", Format::Pdf),
+        (".mobi", Format::Mobi),
+        (".azw", Format::Mobi),
+        (".azw3", Format::Mobi),
+        (".cbz", Format::Cbz),
+    ];
+
+    pub fn from_filename(filename: &str) -> Option<Format> {
+        let filename = filename.to_lowercase();
+        Format::SUFFIXES
+            .iter()
+            .find(|(suffix, _)| filename.ends_with(suffix))
+            .map(|(_, format)| *format)
+    }
+}
+
+/// Reads the metadata of a book with the reader matching its file name.
+pub fn get_metadata(filename: &str) -> Result<BookMetadata> {
+    match Format::from_filename(filename) {
+        Some(Format::Epub) => epub::get_epub_metadata(filename),
+        Some(Format::Fb2) => fb2::get_fb2_metadata(filename),
+        Some(Format::Pdf) => pdf::get_pdf_metadata(filename),
+        Some(Format::Mobi) => mobi::get_mobi_metadata(filename),
+        Some(Format::Cbz) => cbz::get_cbz_metadata(filename),
+        None => Err(Error::Metadata("unsupported book format".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(name: &str, index: Option<f64>) -> Series {
+        Series {
+            name: name.to_string(),
+            index,
+        }
+    }
+
+    #[test]
+    fn parses_series_indices() {
+        assert_eq!(Series::parse_index("2.5"), Some(2.5));
+        assert_eq!(Series::parse_index(" 3 "), Some(3.0));
+        for invalid in ["", "two", "-1", "NaN", "inf"] {
+            assert_eq!(Series::parse_index(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rounds_fractional_indices_down() {
+        assert_eq!(series("Saga", Some(2.5)).numinseries(), Some(2));
+        assert_eq!(series("Saga", Some(3.5)).numinseries(), Some(3));
+        assert_eq!(series("Saga", Some(4.0)).numinseries(), Some(4));
+        assert_eq!(series("Saga", None).numinseries(), None);
+    }
+
+    #[test]
+    fn suffixes_only_fractional_indices() {
+        assert_eq!(
+            series("Saga", Some(2.5)).title_suffix().as_deref(),
+            Some(" [Saga 2.5]")
+        );
+        assert_eq!(series("Saga", Some(2.0)).title_suffix(), None);
+        assert_eq!(series("Saga", None).title_suffix(), None);
+        assert_eq!(series("", Some(2.5)).title_suffix(), None);
+    }
+}
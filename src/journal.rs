@@ -0,0 +1,623 @@
+use std::path::{Path, PathBuf};
+
+use rusqlite::{
+    named_params, params, params_from_iter, types::Value, Connection, DatabaseName, OpenFlags,
+    ToSql, Transaction,
+};
+
+use crate::{
+    backup, database,
+    error::{Error, Result},
+};
+
+const JOURNAL_FILE_NAME: &str = "pbdbfixer-journal.db";
+
+/// Kind of a journaled modification.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Update,
+    Insert,
+    Delete,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Update => "update",
+            Kind::Insert => "insert",
+            Kind::Delete => "delete",
+        }
+    }
+}
+
+/// The book a journaled row belongs to.
+#[derive(Clone, Copy)]
+pub enum BookRef<'a> {
+    /// Column of the row holding the book id.
+    Column(&'a str),
+    /// Id of the book, for rows which don't reference it themselves (e.g.
+    /// genres created for it).
+    Id(i32),
+}
+
+pub struct Run {
+    pub id: i64,
+    pub started: String,
+    pub changes: usize,
+    /// Time the run has been undone at, if it has.
+    pub undone_at: Option<String>,
+}
+
+/// Returns the path of the journal belonging to `db_file`.
+pub fn journal_file(db_file: &Path) -> PathBuf {
+    db_file.with_file_name(JOURNAL_FILE_NAME)
+}
+
+/// Attaches the journal next to `db_file` as database `journal` to `conn`,
+/// so journal entries are written within the same transaction as the fixes.
+pub fn attach(conn: &Connection, db_file: &Path) -> rusqlite::Result<()> {
+    // The database is opened without create flag, which also applies to
+    // attached databases, so a new journal has to be created beforehand.
+    Connection::open(journal_file(db_file))?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS journal",
+        [journal_file(db_file).to_string_lossy()],
+    )?;
+    create_tables(conn)
+}
+
+/// Attaches an in-memory copy of the journal next to `db_file` for dry runs.
+/// The journal file is only read and not created if it doesn't exist yet.
+pub fn attach_copy(conn: &mut Connection, db_file: &Path) -> rusqlite::Result<()> {
+    conn.execute("ATTACH DATABASE ':memory:' AS journal", [])?;
+    let journal_file = journal_file(db_file);
+    if journal_file.exists() {
+        backup::copy_into_memory(&journal_file, conn, DatabaseName::Attached("journal"))?;
+    }
+    create_tables(conn)
+}
+
+/// Creates the journal tables unless they exist already.
+pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS journal.runs (
+          id INTEGER PRIMARY KEY,
+          started TEXT NOT NULL,
+          undone_at TEXT
+        );
+        CREATE TABLE IF NOT EXISTS journal.changes (
+          id INTEGER PRIMARY KEY,
+          run_id INTEGER NOT NULL,
+          kind TEXT NOT NULL,
+          book_id INTEGER,
+          tbl TEXT NOT NULL,
+          row_no INTEGER,
+          col TEXT NOT NULL,
+          old_value,
+          new_value
+        );
+        CREATE TABLE IF NOT EXISTS journal.subjects (
+          book_id INTEGER NOT NULL,
+          name TEXT NOT NULL,
+          PRIMARY KEY (book_id, name)
+        );
+        CREATE TABLE IF NOT EXISTS journal.quarantine (
+          book_id INTEGER PRIMARY KEY,
+          first_seen TEXT NOT NULL,
+          runs INTEGER NOT NULL
+        );"#,
+    )?;
+
+    // Journals written before undone runs were recorded lack the column.
+    let has_undone_at = conn
+        .prepare("SELECT 1 FROM pragma_table_info('runs', 'journal') WHERE name = 'undone_at'")?
+        .exists([])?;
+    if !has_undone_at {
+        conn.execute("ALTER TABLE journal.runs ADD COLUMN undone_at TEXT", [])?;
+    }
+    Ok(())
+}
+
+/// Updates the quarantine of ghost books: books which are no ghosts anymore
+/// are released, the given ghost books are added or their number of runs
+/// is increased. Returns the number of runs each ghost book has been
+/// missing for (in the order of `book_ids`).
+pub fn quarantine(tx: &Transaction, book_ids: &[i32]) -> rusqlite::Result<Vec<i64>> {
+    let list = book_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    tx.execute(
+        &format!(
+            "DELETE FROM journal.quarantine WHERE book_id NOT IN ({})",
+            list
+        ),
+        [],
+    )?;
+
+    let mut runs = Vec::new();
+    for book_id in book_ids {
+        tx.execute(
+            r#"
+            INSERT INTO journal.quarantine (book_id, first_seen, runs)
+              VALUES (:book_id, :now, 1)
+              ON CONFLICT (book_id) DO UPDATE SET runs = runs + 1"#,
+            named_params![":book_id": book_id, ":now": backup::timestamp()],
+        )?;
+        runs.push(tx.query_row(
+            "SELECT runs FROM journal.quarantine WHERE book_id = ?1",
+            [book_id],
+            |r| r.get(0),
+        )?);
+    }
+
+    Ok(runs)
+}
+
+/// Returns the subjects found in the EPUB of book `book_id` by earlier runs.
+pub fn known_subjects(tx: &Transaction, book_id: i32) -> rusqlite::Result<Vec<String>> {
+    let subjects = tx
+        .prepare("SELECT name FROM journal.subjects WHERE book_id = ?1")?
+        .query_map([book_id], |row| row.get(0))?
+        .collect();
+    subjects
+}
+
+/// Remembers the subjects currently found in the EPUB of book `book_id`.
+pub fn record_subjects(
+    tx: &Transaction,
+    book_id: i32,
+    subjects: &[String],
+) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM journal.subjects WHERE book_id = ?1", [book_id])?;
+    for subject in subjects {
+        tx.execute(
+            "INSERT INTO journal.subjects (book_id, name) VALUES (?1, ?2)",
+            params![book_id, subject],
+        )?;
+    }
+    Ok(())
+}
+
+/// Hands the recorded subjects of book `old_id` over to book `new_id` after
+/// it has been relinked, unless subjects have been recorded for that one.
+pub fn move_subjects(tx: &Transaction, old_id: i32, new_id: i32) -> rusqlite::Result<()> {
+    tx.execute(
+        r#"
+        UPDATE journal.subjects SET book_id = ?2
+          WHERE book_id = ?1
+            AND NOT EXISTS (SELECT 1 FROM journal.subjects WHERE book_id = ?2)"#,
+        [old_id, new_id],
+    )?;
+    Ok(())
+}
+
+/// Forgets the recorded subjects of the given books after they have been
+/// removed.
+pub fn forget_subjects(tx: &Transaction, book_ids: &[i32]) -> rusqlite::Result<()> {
+    for book_id in book_ids {
+        tx.execute("DELETE FROM journal.subjects WHERE book_id = ?1", [book_id])?;
+    }
+    Ok(())
+}
+
+/// Removes the given books from the quarantine after they have been purged.
+pub fn release(tx: &Transaction, book_ids: &[i32]) -> rusqlite::Result<()> {
+    for book_id in book_ids {
+        tx.execute(
+            "DELETE FROM journal.quarantine WHERE book_id = ?1",
+            [book_id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Registers a new run and returns its id.
+pub fn start_run(tx: &Transaction) -> rusqlite::Result<i64> {
+    tx.execute(
+        "INSERT INTO journal.runs (started) VALUES (?1)",
+        [backup::timestamp()],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
+
+/// Records the current value of `column` of book `book_id` in `books_impl`
+/// together with the value it is about to be updated to.
+pub fn record_update(
+    tx: &Transaction,
+    run_id: i64,
+    book_id: i32,
+    column: &str,
+    new_value: &dyn ToSql,
+) -> rusqlite::Result<()> {
+    tx.execute(
+        &format!(
+            r#"
+            INSERT INTO journal.changes (run_id, kind, book_id, tbl, col, old_value, new_value)
+              SELECT :run_id, :kind, id, 'books_impl', :column, {}, :new_value
+                FROM books_impl WHERE id = :book_id"#,
+            column
+        ),
+        named_params![
+            ":run_id": run_id,
+            ":kind": Kind::Update.as_str(),
+            ":column": column,
+            ":new_value": new_value,
+            ":book_id": book_id,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Records every column of all rows of `table` matching `condition`. For
+/// inserted rows the values are stored as new values, for rows about to be
+/// deleted as old values.
+pub fn record_rows(
+    tx: &Transaction,
+    run_id: i64,
+    kind: Kind,
+    book: BookRef,
+    table: &str,
+    condition: &str,
+) -> rusqlite::Result<()> {
+    let (book_id, book_param) = match book {
+        BookRef::Column(column) => (column, None),
+        BookRef::Id(id) => ("?1", Some(id)),
+    };
+    let mut stmt = tx.prepare(&format!(
+        "SELECT {}, rowid, * FROM main.{} WHERE {}",
+        book_id, table, condition
+    ))?;
+    let columns = stmt
+        .column_names()
+        .iter()
+        .skip(2)
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>();
+
+    let value_column = match kind {
+        Kind::Delete => "old_value",
+        _ => "new_value",
+    };
+    let mut insert = tx.prepare(&format!(
+        r#"
+        INSERT INTO journal.changes (run_id, kind, book_id, tbl, row_no, col, {})
+          VALUES (:run_id, :kind, :book_id, :table, :row_no, :column, :value)"#,
+        value_column
+    ))?;
+
+    let mut rows = stmt.query(params_from_iter(book_param))?;
+    while let Some(row) = rows.next()? {
+        let book_id: Value = row.get(0)?;
+        let row_no: i64 = row.get(1)?;
+        for (i, column) in columns.iter().enumerate() {
+            let value: Value = row.get(i + 2)?;
+            insert.execute(named_params![
+                ":run_id": run_id,
+                ":kind": kind.as_str(),
+                ":book_id": book_id,
+                ":table": table,
+                ":row_no": row_no,
+                ":column": column,
+                ":value": value,
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists all journaled runs, oldest first.
+pub fn list_runs(db_file: &Path) -> rusqlite::Result<Vec<Run>> {
+    let journal_file = journal_file(db_file);
+    if !journal_file.exists() {
+        return Ok(Vec::new());
+    }
+    let conn = Connection::open_with_flags(journal_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT runs.id, runs.started, COUNT(changes.id), runs.undone_at
+          FROM runs LEFT OUTER JOIN changes
+            ON runs.id = changes.run_id
+          GROUP BY runs.id
+          ORDER BY runs.id"#,
+    )?;
+    let runs = stmt
+        .query_map([], |row| {
+            Ok(Run {
+                id: row.get(0)?,
+                started: row.get(1)?,
+                changes: row.get(2)?,
+                undone_at: row.get(3)?,
+            })
+        })?
+        .collect();
+    runs
+}
+
+struct Entry {
+    kind: String,
+    book_id: Option<i32>,
+    table: String,
+    row_no: Option<i64>,
+    column: String,
+    old_value: Value,
+    new_value: Value,
+}
+
+/// Reverts the changes of run `run_id` to the database `db_file`, see
+/// `revert_run`. Returns the number of reverted modifications.
+pub fn undo_run(db_file: &Path, run_id: i64) -> Result<usize> {
+    // Without journal no run can be found.
+    if !journal_file(db_file).exists() {
+        return Err(Error::Journal(format!("run {} not found", run_id)));
+    }
+    let mut conn = database::open_existing(db_file)?;
+    conn.pragma_update(None, "foreign_keys", 0)?;
+    attach(&conn, db_file)?;
+    let tx = conn.transaction()?;
+    let reverted = revert_run(&tx, run_id)?;
+    tx.commit()?;
+
+    Ok(reverted)
+}
+
+/// Reverts the changes of run `run_id` in reverse order. Updated columns are
+/// only reset if they still hold the value written by the run, and deleted
+/// rows are re-inserted without replacing rows that exist by now, so data
+/// recorded since the run (e.g. reading progress) is kept. Unknown runs and
+/// runs which have been undone already are refused.
+/// Returns the number of reverted modifications.
+pub fn revert_run(tx: &Transaction, run_id: i64) -> Result<usize> {
+    let undone_at = match tx.query_row(
+        "SELECT undone_at FROM journal.runs WHERE id = ?1",
+        [run_id],
+        |r| r.get::<_, Option<String>>(0),
+    ) {
+        Ok(undone_at) => undone_at,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error::Journal(format!("run {} not found", run_id)))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if let Some(undone_at) = undone_at {
+        return Err(Error::Journal(format!(
+            "run {} has already been undone at {}",
+            run_id, undone_at
+        )));
+    }
+
+    let entries = tx
+        .prepare(
+            r#"
+            SELECT kind, book_id, tbl, row_no, col, old_value, new_value
+              FROM journal.changes
+              WHERE run_id = ?1
+              ORDER BY id DESC"#,
+        )?
+        .query_map([run_id], |row| {
+            Ok(Entry {
+                kind: row.get(0)?,
+                book_id: row.get(1)?,
+                table: row.get(2)?,
+                row_no: row.get(3)?,
+                column: row.get(4)?,
+                old_value: row.get(5)?,
+                new_value: row.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut reverted = 0;
+    let mut i = 0;
+    while i < entries.len() {
+        let entry = &entries[i];
+
+        if entry.kind == Kind::Update.as_str() {
+            reverted += tx.execute(
+                &format!(
+                    "UPDATE {} SET {} = :old_value WHERE id = :book_id AND {} IS :new_value",
+                    entry.table, entry.column, entry.column
+                ),
+                named_params![
+                    ":old_value": entry.old_value,
+                    ":book_id": entry.book_id,
+                    ":new_value": entry.new_value,
+                ],
+            )?;
+            i += 1;
+            continue;
+        }
+
+        // Inserted and deleted rows are journaled column by column.
+        let row_entries = entries[i..]
+            .iter()
+            .take_while(|e| {
+                e.kind == entry.kind && e.table == entry.table && e.row_no == entry.row_no
+            })
+            .collect::<Vec<_>>();
+        i += row_entries.len();
+
+        let columns = row_entries
+            .iter()
+            .map(|e| format!("\"{}\"", e.column))
+            .collect::<Vec<_>>();
+
+        if entry.kind == Kind::Delete.as_str() {
+            reverted += tx.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+                    entry.table,
+                    columns.join(", "),
+                    vec!["?"; columns.len()].join(", ")
+                ),
+                params_from_iter(row_entries.iter().map(|e| &e.old_value)),
+            )?;
+        } else {
+            let mut condition = columns
+                .iter()
+                .map(|c| format!("{} IS ?", c))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            // Genres might have been assigned to other books in the meantime.
+            if entry.table == "genres" {
+                condition += " AND id NOT IN (SELECT genreid FROM booktogenre)";
+            }
+            reverted += tx.execute(
+                &format!("DELETE FROM {} WHERE {}", entry.table, condition),
+                params_from_iter(row_entries.iter().map(|e| &e.new_value)),
+            )?;
+        }
+    }
+
+    tx.execute(
+        "UPDATE journal.runs SET undone_at = ?2 WHERE id = ?1",
+        params![run_id, backup::timestamp()],
+    )?;
+
+    Ok(reverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::database;
+
+    fn query<T: rusqlite::types::FromSql>(tx: &Transaction, sql: &str) -> Vec<T> {
+        tx.prepare(sql)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn undo_reverts_updates_and_deletions() {
+        let mut conn = database();
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch(
+            r#"
+            INSERT INTO books_impl (id, title, author) VALUES
+              (1, 'Old title', 'Ann Author'),
+              (2, 'Ghost', 'Bob Builder');
+            INSERT INTO books_settings (bookid, profileid, cpage) VALUES (2, 1, 42);"#,
+        )
+        .unwrap();
+
+        let run_id = start_run(&tx).unwrap();
+        for (column, value) in [("title", "New title"), ("author", "Ann Other")] {
+            record_update(&tx, run_id, 1, column, &value).unwrap();
+            tx.execute(
+                &format!("UPDATE books_impl SET {} = ?1 WHERE id = 1", column),
+                [value],
+            )
+            .unwrap();
+        }
+        for (table, column) in [("books_impl", "id"), ("books_settings", "bookid")] {
+            let condition = format!("{} = 2", column);
+            record_rows(
+                &tx,
+                run_id,
+                Kind::Delete,
+                BookRef::Column(column),
+                table,
+                &condition,
+            )
+            .unwrap();
+            tx.execute(&format!("DELETE FROM {} WHERE {}", table, condition), [])
+                .unwrap();
+        }
+        // changed by the reader after the run
+        tx.execute("UPDATE books_impl SET author = 'Cy Coder' WHERE id = 1", [])
+            .unwrap();
+
+        assert_eq!(revert_run(&tx, run_id).unwrap(), 3);
+        assert_eq!(
+            query::<String>(
+                &tx,
+                "SELECT title || ', ' || author FROM books_impl ORDER BY id"
+            ),
+            ["Old title, Cy Coder", "Ghost, Bob Builder"]
+        );
+        assert_eq!(
+            query::<i32>(&tx, "SELECT cpage FROM books_settings WHERE bookid = 2"),
+            [42]
+        );
+        assert_eq!(
+            query::<i64>(
+                &tx,
+                "SELECT COUNT(*) FROM journal.runs WHERE undone_at IS NOT NULL"
+            ),
+            [1]
+        );
+
+        // A second undo would re-insert the rows deleted in the meantime.
+        tx.execute("DELETE FROM books_settings WHERE bookid = 2", [])
+            .unwrap();
+        assert_eq!(
+            revert_run(&tx, run_id).unwrap_err().to_string(),
+            format!(
+                "journal: run {} has already been undone at {}",
+                run_id,
+                query::<String>(&tx, "SELECT undone_at FROM journal.runs")[0]
+            )
+        );
+        assert_eq!(
+            query::<i64>(&tx, "SELECT COUNT(*) FROM books_settings"),
+            [0]
+        );
+        assert_eq!(
+            revert_run(&tx, run_id + 1).unwrap_err().to_string(),
+            format!("journal: run {} not found", run_id + 1)
+        );
+    }
+
+    #[test]
+    fn adds_the_undone_column_to_old_journals() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            ATTACH DATABASE ':memory:' AS journal;
+            CREATE TABLE journal.runs (id INTEGER PRIMARY KEY, started TEXT NOT NULL);
+            INSERT INTO journal.runs (id, started) VALUES (1, '20220301-181500');"#,
+        )
+        .unwrap();
+
+        create_tables(&conn).unwrap();
+        create_tables(&conn).unwrap();
+        assert_eq!(
+            conn.query_row(
+                "SELECT COUNT(*) FROM journal.runs WHERE undone_at IS NULL",
+                [],
+                |r| r.get::<_, i64>(0)
+            )
+            .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn quarantine_counts_runs_and_releases_books() {
+        let mut conn = database();
+        let tx = conn.transaction().unwrap();
+
+        assert_eq!(quarantine(&tx, &[1, 2]).unwrap(), [1, 1]);
+        assert_eq!(quarantine(&tx, &[2, 3]).unwrap(), [2, 1]);
+        // book 1 has reappeared in the meantime
+        assert_eq!(quarantine(&tx, &[1, 2, 3]).unwrap(), [1, 3, 2]);
+
+        release(&tx, &[2]).unwrap();
+        assert_eq!(
+            query::<i32>(
+                &tx,
+                "SELECT book_id FROM journal.quarantine ORDER BY book_id"
+            ),
+            [1, 3]
+        );
+        assert!(quarantine(&tx, &[]).unwrap().is_empty());
+        assert!(query::<i32>(&tx, "SELECT book_id FROM journal.quarantine").is_empty());
+    }
+}
@@ -1,66 +1,595 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    process,
+};
+
+mod backup;
+mod cbz;
+mod cli;
 mod database;
+mod device;
 mod epub;
+mod error;
+mod fb2;
+mod format;
+mod journal;
+mod mobi;
+mod pdf;
 mod pocketbook;
+mod schema;
+#[cfg(test)]
+mod test_util;
+mod xml;
 
-fn main() {
-    if cfg!(target_arch = "arm") {
-        let res = pocketbook::dialog(
-            pocketbook::Icon::None,
-            "PocketBook has sometimes problems parsing metadata.\n\
-            This app tries to fix some of these issues.\n\
-            (Note: The database file explore-3.db will be altered!)\n\
-            \n\
-            Please be patient - this might take a while.\n\
-            You will see a blank screen during the process.\n\
-            \n\
-            Proceed?",
-            &["Cancel", "Yes"],
+const EXIT_NOTHING_TO_FIX: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_FIXED: i32 = 2;
+
+fn print_changes(changes: &[database::Change]) {
+    let mut last_book_id = None;
+    for change in changes {
+        if last_book_id != Some(change.book_id) {
+            println!("Book {} ({})", change.book_id, change.book);
+            last_book_id = Some(change.book_id);
+        }
+        println!(
+            "  {}: \"{}\" -> \"{}\"",
+            change.column, change.old_value, change.new_value
         );
-        if res == 1 {
-            return;
+    }
+}
+
+fn print_failed_books(failed_books: &[database::FailedBook]) {
+    if !failed_books.is_empty() {
+        println!("\n{} books could not be read:", failed_books.len());
+        for failed_book in failed_books {
+            println!(
+                "  Book {} ({}): {}",
+                failed_book.book_id, failed_book.book, failed_book.reason
+            );
         }
     }
+}
 
-    let stat = database::fix_db_entries();
+fn print_protected_books(protected_books: &[database::ProtectedBook]) {
+    if !protected_books.is_empty() {
+        println!("\n{} books are DRM-protected:", protected_books.len());
+        for protected_book in protected_books {
+            println!(
+                "  Book {} ({}): {}",
+                protected_book.book_id, protected_book.book, protected_book.scheme
+            );
+        }
+    }
+}
 
-    if cfg!(target_arch = "arm") {
-        if stat.anything_fixed() == false {
-            pocketbook::dialog(
+fn print_relink_conflicts(relink_conflicts: &[database::RelinkConflict]) {
+    if !relink_conflicts.is_empty() {
+        println!(
+            "\n{} moved books already had settings in their new entry, \
+            their old ones have been dropped:",
+            relink_conflicts.len()
+        );
+        for conflict in relink_conflicts {
+            println!(
+                "  Book {} ({}) -> book {}: {} rows of {}",
+                conflict.book_id,
+                conflict.book,
+                conflict.new_book_id,
+                conflict.rows,
+                conflict.table
+            );
+        }
+    }
+}
+
+fn print_unmounted_storages(unmounted_storages: &[&str]) {
+    for prefix in unmounted_storages {
+        println!(
+            "\nThe storage {} is not mounted: its books have been skipped \
+            and no books have been relinked or cleaned from the DB.",
+            prefix
+        );
+    }
+}
+
+/// Lists the available backups or, if `selection` is given (either the
+/// number shown in the list or a file name), restores the chosen one.
+fn restore(db_file: &Path, selection: Option<&str>) -> i32 {
+    let backups = backup::list_backups(db_file);
+
+    let selection = match selection {
+        Some(selection) => selection,
+        None => {
+            if backups.is_empty() {
+                println!("No backups found.");
+            }
+            for (i, backup_file) in backups.iter().enumerate() {
+                println!("{}: {}", i + 1, backup_file.display());
+            }
+            return EXIT_NOTHING_TO_FIX;
+        }
+    };
+
+    let backup_file = match selection.parse::<usize>() {
+        Ok(num) if num >= 1 && num <= backups.len() => backups[num - 1].clone(),
+        _ => Path::new(selection).to_path_buf(),
+    };
+
+    match backup::restore_backup(db_file, &backup_file) {
+        Ok(()) => {
+            println!("Restored {}", backup_file.display());
+            EXIT_FIXED
+        }
+        Err(err) => {
+            eprintln!("Could not restore {}: {}", backup_file.display(), err);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Lists the journaled runs or, if `run_id` is given, reverts that run.
+fn undo(db_file: &Path, run_id: Option<&str>) -> i32 {
+    let run_id = match run_id {
+        Some(run_id) => run_id,
+        None => {
+            return match journal::list_runs(db_file) {
+                Ok(runs) => {
+                    for run in runs {
+                        println!(
+                            "{}: {} ({} changes{})",
+                            run.id,
+                            run.started,
+                            run.changes,
+                            match run.undone_at {
+                                Some(undone_at) => format!(", undone at {}", undone_at),
+                                None => String::new(),
+                            }
+                        );
+                    }
+                    EXIT_NOTHING_TO_FIX
+                }
+                Err(err) => {
+                    eprintln!("Could not read the journal: {}", err);
+                    EXIT_ERROR
+                }
+            };
+        }
+    };
+
+    let run_id = match run_id.parse::<i64>() {
+        Ok(run_id) => run_id,
+        Err(_) => {
+            eprintln!("Invalid run id: {}", run_id);
+            return EXIT_ERROR;
+        }
+    };
+
+    match journal::undo_run(db_file, run_id) {
+        Ok(num) => {
+            println!("Run {} undone ({} changes reverted)", run_id, num);
+            EXIT_FIXED
+        }
+        Err(err) => {
+            eprintln!("Could not undo run {}: {}", run_id, err);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Formats a series index, which is empty if the book doesn't give one.
+fn format_index(index: Option<f64>) -> String {
+    index.map(|index| index.to_string()).unwrap_or_default()
+}
+
+fn print_metadata(metadata: &format::BookMetadata) {
+    println!(
+        "\nBook metadata:\n  Title: {} ({})",
+        metadata.title, metadata.title_sort
+    );
+    for title in &metadata.titles {
+        println!("  Title ({}): {}", title.title_type, title.text);
+    }
+    for author in &metadata.authors {
+        println!(
+            "  Author: {} ({}, {})",
+            author.name, author.firstauthor, author.role
+        );
+    }
+    println!(
+        "  Series: {} #{}\n  Genres: {}\n  Languages: {}",
+        metadata.series.name,
+        format_index(metadata.series.index),
+        metadata.genres.join(", "),
+        metadata.languages.join(", ")
+    );
+    for collection in &metadata.collections {
+        println!(
+            "  Collection ({}): {} #{}",
+            collection.collection_type,
+            collection.name,
+            format_index(collection.index)
+        );
+    }
+    for identifier in &metadata.identifiers {
+        println!("  Identifier ({}): {}", identifier.scheme, identifier.value);
+    }
+    for date in &metadata.dates {
+        println!("  Date ({}): {}", date.event, date.value);
+    }
+    println!(
+        "  Publisher: {}\n  Rights: {}\n  Cover: {}\n  Description: {}",
+        metadata.publisher, metadata.rights, metadata.cover, metadata.description
+    );
+    if let Some(protection) = &metadata.protection {
+        println!("  Protection: {}", protection);
+    }
+    for meta in &metadata.metas {
+        match meta.refines.as_str() {
+            "" => println!("  Meta {}: {}", meta.property, meta.value),
+            refines => println!("  Meta {} ({}): {}", meta.property, refines, meta.value),
+        }
+    }
+}
+
+/// Prints the database entries or, if `book_id` is given, the entry of that
+/// book together with the metadata found in its file.
+fn inspect(db_file: &Path, path_map: &device::PathMap, book_id: Option<i32>) -> i32 {
+    let (book_entries, failed_books) = match database::read_book_entries(db_file, path_map) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Could not read the database: {}", err);
+            return EXIT_ERROR;
+        }
+    };
+
+    let book_id = match book_id {
+        Some(book_id) => book_id,
+        None => {
+            for entry in &book_entries {
+                println!(
+                    "{}: {} | {} | {} | {}",
+                    entry.id, entry.filepath, entry.author, entry.title, entry.series
+                );
+            }
+            print_failed_books(&failed_books);
+            return EXIT_NOTHING_TO_FIX;
+        }
+    };
+
+    let entry = match book_entries.iter().find(|entry| entry.id == book_id) {
+        Some(entry) => entry,
+        None => {
+            eprintln!("Book {} not found", book_id);
+            return EXIT_ERROR;
+        }
+    };
+
+    println!(
+        "Database entry of book {}:\n  \
+        File: {}\n  \
+        Title: {}\n  \
+        Sort title: {}\n  \
+        Author: {}\n  \
+        First author: {}\n  \
+        First author letter: {}\n  \
+        Series: {} #{}\n  \
+        Genres: {}",
+        entry.id,
+        entry.filepath,
+        entry.title,
+        entry.sort_title,
+        entry.author,
+        entry.firstauthor,
+        entry.first_author_letter,
+        entry.series,
+        entry.numinseries,
+        entry.genres.join(", ")
+    );
+
+    match format::get_metadata(&entry.filepath) {
+        Ok(metadata) => {
+            print_metadata(&metadata);
+            EXIT_NOTHING_TO_FIX
+        }
+        Err(err) => {
+            eprintln!("\nThe book could not be read: {}", err);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes the database entries as CSV to `file` or to stdout.
+fn export(db_file: &Path, path_map: &device::PathMap, file: Option<&Path>) -> i32 {
+    let (book_entries, _) = match database::read_book_entries(db_file, path_map) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Could not read the database: {}", err);
+            return EXIT_ERROR;
+        }
+    };
+
+    let mut out: Box<dyn Write> = match file {
+        Some(file) => match File::create(file) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                eprintln!("Could not create {}: {}", file.display(), err);
+                return EXIT_ERROR;
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let mut res = writeln!(
+        out,
+        "id,file,title,author,firstauthor,first_author_letter,series,numinseries,genre"
+    );
+    for entry in &book_entries {
+        if res.is_err() {
+            break;
+        }
+        res = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            entry.id,
+            csv_field(&entry.filepath),
+            csv_field(&entry.title),
+            csv_field(&entry.author),
+            csv_field(&entry.firstauthor),
+            csv_field(&entry.first_author_letter),
+            csv_field(&entry.series),
+            entry.numinseries,
+            csv_field(&entry.genres.join(", "))
+        );
+    }
+
+    match res {
+        Ok(()) => EXIT_NOTHING_TO_FIX,
+        Err(err) => {
+            eprintln!("Could not write the export: {}", err);
+            EXIT_ERROR
+        }
+    }
+}
+
+/// Runs the selected fixers and prints the results.
+fn fix(options: &cli::Options) -> i32 {
+    let dry_run = options.fix_options.dry_run;
+    let stat = match database::fix_db_entries(&options.db_file, &options.fix_options) {
+        Ok(stat) => stat,
+        Err(err) => {
+            eprintln!("The database could not be fixed: {}", err);
+            return EXIT_ERROR;
+        }
+    };
+
+    if dry_run || options.verbose {
+        print_changes(&stat.changes);
+    } else {
+        print_changes(
+            &stat
+                .changes
+                .iter()
+                .filter(|change| change.column == "books_impl")
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+    }
+    if dry_run {
+        println!("Dry run - no changes have been written to the database.\n");
+    }
+
+    println!(
+        "Titles fixed:  {}\n\
+        Authors fixed: {}\n\
+        Sorting fixed: {}\n\
+        Genres fixed:  {}\n\
+        Series fixed:  {}\n\
+        Series indices fixed: {}\n\
+        Books relinked: {}\n\
+        Books cleaned from DB: {}\n\
+        Books in quarantine:   {}",
+        &stat.titles_fixed,
+        &stat.authors_fixed,
+        &stat.sorting_fixed,
+        &stat.genres_fixed,
+        &stat.series_fixed,
+        &stat.series_indices_fixed,
+        &stat.books_relinked,
+        &stat.ghost_books_cleaned,
+        &stat.ghost_books_quarantined
+    );
+    print_failed_books(&stat.failed_books);
+    print_protected_books(&stat.protected_books);
+    print_relink_conflicts(&stat.relink_conflicts);
+    print_unmounted_storages(&stat.unmounted_storages);
+    if let Some(backup_file) = &stat.backup_file {
+        println!("Backup: {}", backup_file.display());
+        println!(
+            "Run id: {} (revert with `undo {}`)",
+            stat.run_id, stat.run_id
+        );
+    }
+
+    if !stat.failed_books.is_empty() {
+        EXIT_ERROR
+    } else if stat.anything_fixed() {
+        EXIT_FIXED
+    } else {
+        EXIT_NOTHING_TO_FIX
+    }
+}
+
+/// The interactive mode used when the app is started on the device.
+fn run_on_device() {
+    let db_file = Path::new(database::DATABASE_FILE);
+    let last_backup = backup::list_backups(db_file).pop();
+
+    let mut buttons = vec!["Cancel", "Yes"];
+    if last_backup.is_some() {
+        buttons.push("Restore last backup");
+    }
+
+    let res = pocketbook::dialog(
+        pocketbook::Icon::None,
+        "PocketBook has sometimes problems parsing metadata.\n\
+        This app tries to fix some of these issues.\n\
+        (Note: The database file explore-3.db will be altered!)\n\
+        \n\
+        Please be patient - this might take a while.\n\
+        You will see a blank screen during the process.\n\
+        \n\
+        Proceed?",
+        &buttons,
+    );
+    // 1 = Cancel, 2 = Yes, 3 = Restore; anything else (e.g. the dialog
+    // couldn't be shown) cancels as well.
+    if let (3, Some(backup_file)) = (res, last_backup) {
+        match backup::restore_backup(db_file, &backup_file) {
+            Ok(()) => pocketbook::dialog(
                 pocketbook::Icon::Info,
-                "The database seems to be ok.\n\
-                    Nothing had to be fixed.",
+                &format!("Backup restored:\n{}", backup_file.display()),
                 &["OK"],
-            );
-        } else {
+            ),
+            Err(err) => pocketbook::dialog(
+                pocketbook::Icon::Attention,
+                &format!("The backup could not be restored:\n{}", err),
+                &["OK"],
+            ),
+        };
+        return;
+    }
+    if res != 2 {
+        return;
+    }
+
+    let fix_options = database::FixOptions {
+        fixers: database::Fixer::ALL.to_vec(),
+        dry_run: false,
+        path_map: device::PathMap::identity(),
+        ghost_runs: database::DEFAULT_GHOST_RUNS,
+        genre_policy: database::GenrePolicy::Add,
+        series_suffix: false,
+        backups: backup::DEFAULT_BACKUPS,
+    };
+    let stat = match database::fix_db_entries(db_file, &fix_options) {
+        Ok(stat) => stat,
+        Err(err) => {
             pocketbook::dialog(
-                pocketbook::Icon::Info,
+                pocketbook::Icon::X,
                 &format!(
-                    "Authors fixed: {}\n\
-                    Sorting fixed: {}\n\
-                    Genres fixed:  {}\n\
-                    Series fixed:  {}\n\
-                    Books cleaned from DB: {}",
-                    &stat.authors_fixed,
-                    &stat.sorting_fixed,
-                    &stat.genres_fixed,
-                    &stat.series_fixed,
-                    &stat.ghost_books_cleaned
+                    "The database could not be fixed:\n{}\n\
+                    \n\
+                    No changes have been made.",
+                    err
                 ),
                 &["OK"],
             );
+            return;
         }
+    };
+
+    let mut failed = if stat.failed_books.is_empty() {
+        String::new()
     } else {
-        println!(
-            "Authors fixed: {}\n\
-            Sorting fixed: {}\n\
-            Genres fixed:  {}\n\
-            Series fixed:  {}\n\
-            Books cleaned from DB: {}",
-            &stat.authors_fixed,
-            &stat.sorting_fixed,
-            &stat.genres_fixed,
-            &stat.series_fixed,
-            &stat.ghost_books_cleaned
+        format!("\n\n{} books could not be read.", stat.failed_books.len())
+    };
+    if !stat.protected_books.is_empty() {
+        failed.push_str(&format!(
+            "\n\n{} books are DRM-protected.",
+            stat.protected_books.len()
+        ));
+    }
+    if !stat.relink_conflicts.is_empty() {
+        failed.push_str(&format!(
+            "\n\n{} moved books kept the settings\nof their new entry.",
+            stat.relink_conflicts.len()
+        ));
+    }
+    if !stat.unmounted_storages.is_empty() {
+        failed.push_str(
+            "\n\nThe SD card is not inserted:\n\
+            its books have been skipped.",
+        );
+    }
+    if !stat.anything_fixed() {
+        pocketbook::dialog(
+            pocketbook::Icon::Info,
+            &format!(
+                "The database seems to be ok.\n\
+                Nothing had to be fixed.{}",
+                failed
+            ),
+            &["OK"],
         );
+    } else {
+        pocketbook::dialog(
+            pocketbook::Icon::Info,
+            &format!(
+                "Titles fixed:  {}\n\
+                Authors fixed: {}\n\
+                Sorting fixed: {}\n\
+                Genres fixed:  {}\n\
+                Series fixed:  {}\n\
+                Series indices fixed: {}\n\
+                Books relinked: {}\n\
+                Books cleaned from DB: {}\n\
+                Books in quarantine:   {}{}",
+                &stat.titles_fixed,
+                &stat.authors_fixed,
+                &stat.sorting_fixed,
+                &stat.genres_fixed,
+                &stat.series_fixed,
+                &stat.series_indices_fixed,
+                &stat.books_relinked,
+                &stat.ghost_books_cleaned,
+                &stat.ghost_books_quarantined,
+                failed
+            ),
+            &["OK"],
+        );
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    if cfg!(target_arch = "arm") && args.is_empty() {
+        run_on_device();
+        return;
     }
+
+    let options = match cli::parse(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, cli::USAGE);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let db_file = options.db_file.as_path();
+    let path_map = &options.fix_options.path_map;
+    let code = match &options.command {
+        cli::Command::Fix | cli::Command::Check => fix(&options),
+        cli::Command::Inspect(book_id) => inspect(db_file, path_map, *book_id),
+        cli::Command::Export(file) => export(db_file, path_map, file.as_deref()),
+        cli::Command::Restore(selection) => restore(db_file, selection.as_deref()),
+        cli::Command::Undo(run_id) => undo(db_file, run_id.as_deref()),
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            EXIT_NOTHING_TO_FIX
+        }
+    };
+
+    process::exit(code);
 }
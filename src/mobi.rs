@@ -0,0 +1,348 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::{
+    error::{Error, Result},
+    format::{Author, BookMetadata, Identifier, Protection},
+};
+
+/// Type and creator of MOBI, AZW and AZW3 files in the PalmDB header.
+const MOBI_TYPE_CREATOR: &[u8] = b"BOOKMOBI";
+const PDB_HEADER_LEN: usize = 78;
+/// Size of the PalmDOC header preceding the MOBI header in record 0.
+const PALMDOC_HEADER_LEN: usize = 16;
+
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_ISBN: u32 = 104;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_LANGUAGE: u32 = 524;
+
+/// Characters 0x80 to 0x9F of windows-1252; the others match Latin-1.
+const CP1252_HIGH: &str = "€\u{fffd}‚ƒ„…†‡ˆ‰Š‹Œ\u{fffd}Ž\u{fffd}\u{fffd}‘’“”•–—˜™š›œ\u{fffd}žŸ";
+
+/// Windows language ids as used in the MOBI header's locale, for books
+/// without EXTH language record.
+const LANGUAGES: &[(u32, &str)] = &[
+    (4, "zh"),
+    (5, "cs"),
+    (6, "da"),
+    (7, "de"),
+    (8, "el"),
+    (9, "en"),
+    (10, "es"),
+    (11, "fi"),
+    (12, "fr"),
+    (14, "hu"),
+    (16, "it"),
+    (17, "ja"),
+    (18, "ko"),
+    (19, "nl"),
+    (20, "no"),
+    (21, "pl"),
+    (22, "pt"),
+    (25, "ru"),
+    (29, "sv"),
+    (31, "tr"),
+    (34, "uk"),
+];
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn invalid() -> Error {
+    Error::Metadata("invalid MOBI header".to_string())
+}
+
+/// Adds an offset or length taken from the file, which may overflow `usize`
+/// on 32-bit targets.
+fn add(offset: usize, len: usize) -> Result<usize> {
+    offset.checked_add(len).ok_or_else(invalid)
+}
+
+/// Decodes a string in the text encoding given in the MOBI header.
+fn decode_string(bytes: &[u8], is_utf8: bool) -> String {
+    let text: String = if is_utf8 {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0x9f => CP1252_HIGH.chars().nth(byte as usize - 0x80).unwrap(),
+                _ => byte as char,
+            })
+            .collect()
+    };
+    text.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+/// Returns record 0 of the PalmDB database, which holds the MOBI header.
+fn read_record0<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = [0u8; PDB_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if &header[60..68] != MOBI_TYPE_CREATOR {
+        return Err(Error::Metadata("not a MOBI document".to_string()));
+    }
+    let num_records = u16_at(&header, 76).ok_or_else(invalid)?;
+    if num_records == 0 {
+        return Err(invalid());
+    }
+
+    // offsets of records 0 and 1
+    let mut record_list = [0u8; 16];
+    let record_list_len = if num_records > 1 { 16 } else { 8 };
+    reader.read_exact(&mut record_list[..record_list_len])?;
+    let start = u32_at(&record_list, 0).ok_or_else(invalid)?;
+    let end = if num_records > 1 {
+        u32_at(&record_list, 8).ok_or_else(invalid)?
+    } else {
+        reader.seek(SeekFrom::End(0))? as u32
+    };
+    if end <= start {
+        return Err(invalid());
+    }
+
+    let mut record0 = Vec::new();
+    reader.seek(SeekFrom::Start(u64::from(start)))?;
+    reader
+        .take(u64::from(end - start))
+        .read_to_end(&mut record0)?;
+    Ok(record0)
+}
+
+fn read_mobi_metadata<R: Read + Seek>(mut reader: R) -> Result<BookMetadata> {
+    let record0 = read_record0(&mut reader)?;
+    let mut book_meta = BookMetadata::new();
+
+    // PalmDOC header: 1 = old Mobipocket encryption, 2 = Mobipocket DRM
+    match u16_at(&record0, 12) {
+        Some(0) | None => (),
+        Some(_) => book_meta.protection = Some(Protection::Drm("Mobipocket".to_string())),
+    }
+
+    let mobi = PALMDOC_HEADER_LEN;
+    if record0.get(mobi..mobi + 4) != Some(b"MOBI") {
+        return Err(invalid());
+    }
+    let header_len = u32_at(&record0, mobi + 4).ok_or_else(invalid)? as usize;
+    let is_utf8 = u32_at(&record0, mobi + 12) == Some(65001);
+
+    // The full name is the title unless EXTH has an updated one.
+    if let (Some(offset), Some(len)) = (u32_at(&record0, mobi + 68), u32_at(&record0, mobi + 72)) {
+        let end = add(offset as usize, len as usize)?;
+        if let Some(full_name) = record0.get(offset as usize..end) {
+            book_meta.title = decode_string(full_name, is_utf8);
+        }
+    }
+    let locale = u32_at(&record0, mobi + 76).unwrap_or_default();
+
+    let has_exth = u32_at(&record0, mobi + 112).unwrap_or_default() & 0x40 != 0;
+    let exth = add(mobi, header_len)?;
+    if has_exth && record0.get(exth..add(exth, 4)?) == Some(b"EXTH") {
+        let count = u32_at(&record0, add(exth, 8)?).ok_or_else(invalid)?;
+        let mut offset = add(exth, 12)?;
+        for _ in 0..count {
+            let (record_type, len) =
+                match (u32_at(&record0, offset), u32_at(&record0, add(offset, 4)?)) {
+                    (Some(record_type), Some(len)) if len >= 8 => (record_type, len as usize),
+                    _ => break,
+                };
+            let end = add(offset, len)?;
+            let value = match record0.get(add(offset, 8)?..end) {
+                Some(data) => decode_string(data, is_utf8),
+                None => break,
+            };
+            offset = end;
+            if value.is_empty() {
+                continue;
+            }
+
+            match record_type {
+                EXTH_AUTHOR => book_meta.authors.push(Author {
+                    name: value,
+                    firstauthor: String::new(),
+                    role: "aut".to_string(),
+                    is_creator: true,
+                }),
+                EXTH_PUBLISHER => book_meta.publisher = value,
+                EXTH_DESCRIPTION => book_meta.description = value,
+                EXTH_ISBN => book_meta.identifiers.push(Identifier {
+                    scheme: "ISBN".to_string(),
+                    value,
+                }),
+                EXTH_SUBJECT if !book_meta.genres.contains(&value) => book_meta.genres.push(value),
+                EXTH_UPDATED_TITLE => book_meta.title = value,
+                EXTH_LANGUAGE => book_meta.languages.push(value),
+                _ => (),
+            }
+        }
+    }
+
+    if book_meta.languages.is_empty() {
+        if let Some((_, language)) = LANGUAGES.iter().find(|(id, _)| *id == locale & 0xff) {
+            book_meta.languages.push(language.to_string());
+        }
+    }
+
+    Ok(book_meta)
+}
+
+/// Reads the metadata of a MOBI, AZW or AZW3 file from its MOBI header and
+/// EXTH records.
+pub fn get_mobi_metadata(filename: &str) -> Result<BookMetadata> {
+    read_mobi_metadata(fs::File::open(filename)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MOBI_HEADER_LEN: usize = 232;
+
+    /// Builds a MOBI file with the given EXTH records and full name.
+    fn mobi(exth_records: &[(u32, &[u8])], full_name: &[u8], encoding: u32, drm: u16) -> Vec<u8> {
+        let mut exth = Vec::new();
+        for (record_type, data) in exth_records {
+            exth.extend(record_type.to_be_bytes());
+            exth.extend((data.len() as u32 + 8).to_be_bytes());
+            exth.extend(*data);
+        }
+        let exth_len = 12 + exth.len();
+
+        let mut record0 = vec![0u8; PALMDOC_HEADER_LEN + MOBI_HEADER_LEN];
+        record0[12..14].copy_from_slice(&drm.to_be_bytes());
+        let mobi = PALMDOC_HEADER_LEN;
+        record0[mobi..mobi + 4].copy_from_slice(b"MOBI");
+        record0[mobi + 4..mobi + 8].copy_from_slice(&(MOBI_HEADER_LEN as u32).to_be_bytes());
+        record0[mobi + 12..mobi + 16].copy_from_slice(&encoding.to_be_bytes());
+        let full_name_offset = (record0.len() + exth_len) as u32;
+        record0[mobi + 68..mobi + 72].copy_from_slice(&full_name_offset.to_be_bytes());
+        record0[mobi + 72..mobi + 76].copy_from_slice(&(full_name.len() as u32).to_be_bytes());
+        // locale: German (Germany)
+        record0[mobi + 76..mobi + 80].copy_from_slice(&0x0407u32.to_be_bytes());
+        record0[mobi + 112..mobi + 116].copy_from_slice(&0x50u32.to_be_bytes());
+        record0.extend(b"EXTH");
+        record0.extend((exth_len as u32).to_be_bytes());
+        record0.extend((exth_records.len() as u32).to_be_bytes());
+        record0.extend(exth);
+        record0.extend(full_name);
+        record0.extend([0, 0]);
+
+        let mut data = vec![0u8; PDB_HEADER_LEN];
+        data[..9].copy_from_slice(b"Some_Book");
+        data[60..68].copy_from_slice(MOBI_TYPE_CREATOR);
+        data[76..78].copy_from_slice(&2u16.to_be_bytes());
+        let record0_offset = PDB_HEADER_LEN + 16 + 2;
+        data.extend((record0_offset as u32).to_be_bytes());
+        data.extend([0, 0, 0, 0]);
+        data.extend(((record0_offset + record0.len()) as u32).to_be_bytes());
+        data.extend([0, 0, 0, 2]);
+        data.extend([0, 0]);
+        data.extend(record0);
+        data.extend(b"record 1");
+        data
+    }
+
+    #[test]
+    fn reads_exth_records() {
+        let data = mobi(
+            &[
+                (EXTH_AUTHOR, "Jörg Author".as_bytes()),
+                (EXTH_AUTHOR, b"Second Author"),
+                (EXTH_PUBLISHER, b"Publisher"),
+                (EXTH_SUBJECT, b"Fiction"),
+                (EXTH_SUBJECT, b"Fiction"),
+                (EXTH_SUBJECT, b"Crime"),
+                (EXTH_ISBN, b"9783161484100"),
+                (201, &[0, 0, 0, 1]),
+                (EXTH_UPDATED_TITLE, "Der Titel – aktualisiert".as_bytes()),
+                (EXTH_LANGUAGE, b"de-DE"),
+            ],
+            b"Der Titel",
+            65001,
+            0,
+        );
+
+        let book_meta = read_mobi_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Der Titel – aktualisiert");
+        assert_eq!(
+            book_meta
+                .authors
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            ["Jörg Author", "Second Author"]
+        );
+        assert_eq!(book_meta.publisher, "Publisher");
+        assert_eq!(book_meta.genres, ["Fiction", "Crime"]);
+        assert_eq!(book_meta.identifiers[0].value, "9783161484100");
+        assert_eq!(book_meta.languages, ["de-DE"]);
+        assert_eq!(book_meta.protection, None);
+    }
+
+    #[test]
+    fn falls_back_to_the_mobi_header() {
+        let data = mobi(
+            &[(EXTH_AUTHOR, b"J\xf6rg \x96 Author")],
+            b"Caf\xe9",
+            1252,
+            2,
+        );
+
+        let book_meta = read_mobi_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Café");
+        assert_eq!(book_meta.authors[0].name, "Jörg – Author");
+        assert_eq!(book_meta.languages, ["de"]);
+        assert_eq!(
+            book_meta.protection,
+            Some(Protection::Drm("Mobipocket".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_offsets_beyond_the_address_space() {
+        assert_eq!(add(usize::MAX - 4, 4).unwrap(), usize::MAX);
+        assert!(matches!(add(usize::MAX - 4, 5), Err(Error::Metadata(_))));
+        assert_eq!(u32_at(&[0; 8], usize::MAX - 2), None);
+
+        // Offsets and lengths at the maximum of their fields must not panic,
+        // whatever the width of `usize`.
+        let mut data = mobi(&[(EXTH_AUTHOR, b"Author")], b"Title", 65001, 0);
+        let mobi = PDB_HEADER_LEN + 18 + PALMDOC_HEADER_LEN;
+        data[mobi + 72..mobi + 76].copy_from_slice(&u32::MAX.to_be_bytes());
+        let exth_record = mobi + MOBI_HEADER_LEN + 12;
+        data[exth_record + 4..exth_record + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        match read_mobi_metadata(Cursor::new(data)) {
+            Ok(book_meta) => assert!(book_meta.title.is_empty() && book_meta.authors.is_empty()),
+            Err(err) => assert!(matches!(err, Error::Metadata(_))),
+        }
+    }
+
+    #[test]
+    fn rejects_other_palm_databases() {
+        let mut data = mobi(&[], b"", 65001, 0);
+        data[60..68].copy_from_slice(b"TEXtREAd");
+
+        assert_eq!(
+            read_mobi_metadata(Cursor::new(data))
+                .unwrap_err()
+                .to_string(),
+            "invalid metadata: not a MOBI document"
+        );
+    }
+}
@@ -0,0 +1,1509 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{Read, Seek, SeekFrom},
+};
+
+use flate2::read::ZlibDecoder;
+use quick_xml::{events::Event, Reader};
+
+use crate::{
+    error::{Error, Result},
+    format::{Author, BookMetadata, Collection, Protection, Series},
+    xml,
+};
+
+const RDF_NAMESPACE: &[u8] = b"http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const CALIBRE_NAMESPACE: &[u8] = b"http://calibre-ebook.com/xmp-namespace";
+const CALIBRE_SI_NAMESPACE: &[u8] = b"http://calibre-ebook.com/xmp-namespace-series-index";
+/// Followed by the PRISM version, e.g. `2.0/`.
+const PRISM_NAMESPACE_PREFIX: &[u8] = b"http://prismstandard.org/namespaces/basic/";
+
+/// Characters 0x80 to 0xA0 of PDFDocEncoding; the others match Latin-1.
+const PDF_DOC_HIGH: &str = "•†‡…—–ƒ⁄‹›−‰„“”‘’‚™ﬁﬂŁŒŠŸŽıłœšž\u{fffd}€";
+
+/// Size of the first chunk read when parsing an object.
+const CHUNK_SIZE: usize = 4096;
+
+/// Maximum nesting of arrays and dictionaries, so crafted files can't
+/// overflow the stack of the recursive parser.
+const MAX_DEPTH: usize = 256;
+
+/// Upper bound for everything read into memory at once, i.e. objects,
+/// cross-reference tables and (decoded) streams, so malformed files can't
+/// exhaust the memory of the reader.
+const MAX_OBJECT_LEN: usize = 64 << 20;
+
+/// Size of the chunks read when scanning a file for its objects.
+const SCAN_CHUNK_SIZE: usize = 1 << 20;
+
+/// Overlap of the scanned chunks, enough for an object header followed by
+/// the dictionary of a cross-reference stream.
+const SCAN_OVERLAP: usize = CHUNK_SIZE;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Object {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Name(String),
+    String(Vec<u8>),
+    Array(Vec<Object>),
+    Dictionary(Dictionary),
+    Stream(Dictionary, Vec<u8>),
+    /// An indirect reference; the generation number is ignored.
+    Reference(u32),
+}
+
+type Dictionary = HashMap<String, Object>;
+
+impl Object {
+    fn as_dictionary(&self) -> Option<&Dictionary> {
+        match self {
+            Object::Dictionary(dictionary) | Object::Stream(dictionary, _) => Some(dictionary),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Object::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    fn as_name(&self) -> Option<&str> {
+        match self {
+            Object::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte) && !is_delimiter(byte)
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn rfind(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+/// Decodes a PDF text string: UTF-16BE or UTF-8 with byte order mark,
+/// otherwise PDFDocEncoding.
+fn decode_text_string(bytes: &[u8]) -> String {
+    match bytes {
+        [0xfe, 0xff, utf16 @ ..] => String::from_utf16_lossy(
+            &utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        [0xef, 0xbb, 0xbf, utf8 @ ..] => String::from_utf8_lossy(utf8).into_owned(),
+        _ => bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0xa0 => PDF_DOC_HIGH.chars().nth(byte as usize - 0x80).unwrap(),
+                _ => byte as char,
+            })
+            .collect(),
+    }
+}
+
+/// A parser for the object syntax of PDF files.
+struct Lexer<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Number of arrays and dictionaries currently open.
+    depth: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Lexer {
+            data,
+            pos: pos.min(data.len()),
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if byte == b'%' {
+                while matches!(self.peek(), Some(byte) if byte != b'\n' && byte != b'\r') {
+                    self.pos += 1;
+                }
+            } else if is_whitespace(byte) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Reads a run of regular characters, i.e. a number or a keyword.
+    fn token(&mut self) -> &'a [u8] {
+        self.skip_whitespace();
+        let start = self.pos;
+        while matches!(self.peek(), Some(byte) if is_regular(byte)) {
+            self.pos += 1;
+        }
+        &self.data[start..self.pos]
+    }
+
+    fn keyword(&mut self, keyword: &[u8]) -> bool {
+        let pos = self.pos;
+        if self.token() == keyword {
+            true
+        } else {
+            self.pos = pos;
+            false
+        }
+    }
+
+    fn integer(&mut self) -> Option<u64> {
+        let pos = self.pos;
+        let integer = std::str::from_utf8(self.token())
+            .ok()
+            .and_then(|token| token.parse().ok());
+        if integer.is_none() {
+            self.pos = pos;
+        }
+        integer
+    }
+
+    /// Reads the `N G obj` header of an indirect object.
+    fn object_header(&mut self) -> Option<u32> {
+        let number = self.integer()?;
+        self.integer()?;
+        self.keyword(b"obj").then_some(number as u32)
+    }
+
+    fn object(&mut self) -> Option<Object> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'/' => {
+                self.pos += 1;
+                Some(Object::Name(self.name()))
+            }
+            b'(' => self.literal_string(),
+            b'<' if self.data.get(self.pos + 1) == Some(&b'<') => {
+                self.pos += 2;
+                self.nested(Self::dictionary).map(Object::Dictionary)
+            }
+            b'<' => self.hex_string(),
+            b'[' => {
+                self.pos += 1;
+                self.nested(Self::array).map(Object::Array)
+            }
+            _ => match self.token() {
+                b"true" => Some(Object::Bool(true)),
+                b"false" => Some(Object::Bool(false)),
+                b"null" => Some(Object::Null),
+                token => {
+                    let number = std::str::from_utf8(token).ok()?.parse::<f64>().ok()?;
+                    let pos = self.pos;
+                    if self.integer().is_some() && self.keyword(b"R") {
+                        return Some(Object::Reference(number as u32));
+                    }
+                    self.pos = pos;
+                    Some(Object::Number(number))
+                }
+            },
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = Vec::new();
+        while let Some(byte) = self.peek().filter(|byte| is_regular(*byte)) {
+            let hex = self
+                .data
+                .get(self.pos + 1..self.pos + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match (byte, hex) {
+                (b'#', Some(byte)) => {
+                    name.push(byte);
+                    self.pos += 3;
+                }
+                _ => {
+                    name.push(byte);
+                    self.pos += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&name).into_owned()
+    }
+
+    /// Runs `parse` on an array or dictionary unless too many are open.
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Option<T>) -> Option<T> {
+        if self.depth >= MAX_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn array(&mut self) -> Option<Vec<Object>> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek()? == b']' {
+                self.pos += 1;
+                return Some(items);
+            }
+            items.push(self.object()?);
+        }
+    }
+
+    fn dictionary(&mut self) -> Option<Dictionary> {
+        let mut dictionary = Dictionary::new();
+        loop {
+            self.skip_whitespace();
+            match self.data.get(self.pos..self.pos + 2)? {
+                b">>" => {
+                    self.pos += 2;
+                    return Some(dictionary);
+                }
+                [b'/', _] => {
+                    self.pos += 1;
+                    let key = self.name();
+                    let value = self.object()?;
+                    dictionary.insert(key, value);
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Option<Object> {
+        self.pos += 1;
+        let mut depth = 1;
+        let mut string = Vec::new();
+        loop {
+            let byte = self.peek()?;
+            self.pos += 1;
+            match byte {
+                b'\\' => {
+                    let escaped = self.peek()?;
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => string.push(b'\n'),
+                        b'r' => string.push(b'\r'),
+                        b't' => string.push(b'\t'),
+                        b'b' => string.push(0x08),
+                        b'f' => string.push(0x0c),
+                        b'0'..=b'7' => {
+                            let mut value = u32::from(escaped - b'0');
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(digit @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(digit - b'0');
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            string.push(value as u8);
+                        }
+                        // line continuation
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => (),
+                        _ => string.push(escaped),
+                    }
+                }
+                b'(' => {
+                    depth += 1;
+                    string.push(byte);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(Object::String(string));
+                    }
+                    string.push(byte);
+                }
+                _ => string.push(byte),
+            }
+        }
+    }
+
+    fn hex_string(&mut self) -> Option<Object> {
+        self.pos += 1;
+        let mut digits = Vec::new();
+        loop {
+            let byte = self.peek()?;
+            self.pos += 1;
+            match byte {
+                b'>' => break,
+                _ if byte.is_ascii_hexdigit() => digits.push(byte),
+                _ if is_whitespace(byte) => (),
+                _ => return None,
+            }
+        }
+        if digits.len() % 2 == 1 {
+            digits.push(b'0');
+        }
+        Some(Object::String(
+            digits
+                .chunks(2)
+                .filter_map(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                .collect(),
+        ))
+    }
+}
+
+/// Reverts the PNG predictors used e.g. by cross-reference streams.
+fn png_unpredict(data: &[u8], bytes_per_pixel: usize, row_len: usize) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; row_len];
+    for row in data.chunks(row_len + 1) {
+        let (predictor, row) = match row.split_first() {
+            Some(split) => split,
+            None => break,
+        };
+        let mut current = row.to_vec();
+        current.resize(row_len, 0);
+        for i in 0..row_len {
+            let left = if i >= bytes_per_pixel {
+                current[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let up = previous[i];
+            let up_left = if i >= bytes_per_pixel {
+                previous[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let prediction = match predictor {
+                1 => left,
+                2 => up,
+                3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+                4 => {
+                    let estimate = i16::from(left) + i16::from(up) - i16::from(up_left);
+                    let distances = [
+                        (estimate - i16::from(left)).abs(),
+                        (estimate - i16::from(up)).abs(),
+                        (estimate - i16::from(up_left)).abs(),
+                    ];
+                    if distances[0] <= distances[1] && distances[0] <= distances[2] {
+                        left
+                    } else if distances[1] <= distances[2] {
+                        up
+                    } else {
+                        up_left
+                    }
+                }
+                _ => 0,
+            };
+            current[i] = current[i].wrapping_add(prediction);
+        }
+        decoded.extend_from_slice(&current);
+        previous = current;
+    }
+    decoded
+}
+
+/// Applies the filters of a stream. Only `FlateDecode` is supported, which
+/// is what cross-reference, object and metadata streams use in practice.
+fn decode_stream(dictionary: &Dictionary, data: Vec<u8>) -> Result<Vec<u8>> {
+    let filters = match dictionary.get("Filter") {
+        Some(Object::Array(filters)) => filters.iter().filter_map(Object::as_name).collect(),
+        Some(filter) => filter.as_name().into_iter().collect(),
+        None => Vec::new(),
+    };
+    let params = match dictionary.get("DecodeParms") {
+        Some(Object::Array(params)) => params.first(),
+        params => params,
+    }
+    .and_then(Object::as_dictionary);
+
+    let mut data = data;
+    for filter in filters {
+        if filter != "FlateDecode" && filter != "Fl" {
+            return Err(Error::Metadata(format!(
+                "unsupported PDF filter {}",
+                filter
+            )));
+        }
+        let mut inflated = Vec::new();
+        let mut decoder = ZlibDecoder::new(data.as_slice()).take(MAX_OBJECT_LEN as u64 + 1);
+        // Truncated streams are common, so take what could be inflated.
+        if let Err(err) = decoder.read_to_end(&mut inflated) {
+            if inflated.is_empty() {
+                return Err(err.into());
+            }
+        }
+        if inflated.len() > MAX_OBJECT_LEN {
+            return Err(Error::Metadata("PDF stream too large".to_string()));
+        }
+        data = inflated;
+
+        let param = |key, default| {
+            params
+                .and_then(|params| params.get(key))
+                .and_then(Object::as_number)
+                .map_or(default, |value| value as usize)
+        };
+        if param("Predictor", 1) >= 10 {
+            let invalid = || Error::Metadata("invalid PDF predictor parameters".to_string());
+            let bits_per_pixel = param("Colors", 1)
+                .checked_mul(param("BitsPerComponent", 8))
+                .ok_or_else(invalid)?;
+            let row_len = bits_per_pixel
+                .checked_mul(param("Columns", 1))
+                .map(|bits| bits.div_ceil(8))
+                .filter(|row_len| (1..=MAX_OBJECT_LEN).contains(row_len))
+                .ok_or_else(invalid)?;
+            data = png_unpredict(&data, (bits_per_pixel / 8).max(1), row_len);
+        }
+    }
+    Ok(data)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum XrefEntry {
+    Offset(u64),
+    /// Object number of the object stream and index within it.
+    Compressed(u32, usize),
+}
+
+/// Lazy access to the objects of a PDF file.
+struct Pdf<R> {
+    reader: R,
+    len: u64,
+    xref: HashMap<u32, XrefEntry>,
+    trailer: Dictionary,
+    object_streams: HashMap<u32, Vec<Object>>,
+    resolving_length: bool,
+}
+
+impl<R: Read + Seek> Pdf<R> {
+    fn open(mut reader: R) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut pdf = Pdf {
+            reader,
+            len,
+            xref: HashMap::new(),
+            trailer: Dictionary::new(),
+            object_streams: HashMap::new(),
+            resolving_length: false,
+        };
+
+        let head = pdf.read_at(0, 1024)?;
+        if find(&head, b"%PDF-").is_none() {
+            return Err(Error::Metadata("not a PDF document".to_string()));
+        }
+
+        if pdf.read_xref().is_err() || !pdf.trailer.contains_key("Root") {
+            pdf.rebuild_xref()?;
+        }
+        Ok(pdf)
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.reader.seek(SeekFrom::Start(offset))?;
+        (&mut self.reader).take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Runs `parse` on the data at `offset`, reading more of the file as
+    /// long as the data ends before the parser is done. Returns the result
+    /// and the position after it.
+    fn parse_at<T>(
+        &mut self,
+        offset: u64,
+        parse: impl Fn(&mut Lexer) -> Option<T>,
+    ) -> Result<(T, usize)> {
+        let mut len = CHUNK_SIZE;
+        loop {
+            let data = self.read_at(offset, len)?;
+            let is_complete = data.len() < len;
+            let mut lexer = Lexer::new(&data, 0);
+            match parse(&mut lexer) {
+                Some(result) if is_complete || lexer.pos < data.len() => {
+                    return Ok((result, lexer.pos))
+                }
+                None if is_complete || len >= MAX_OBJECT_LEN => {
+                    return Err(Error::Metadata(format!(
+                        "invalid PDF object at offset {}",
+                        offset
+                    )))
+                }
+                _ => len *= 4,
+            }
+        }
+    }
+
+    fn object_at(&mut self, offset: u64) -> Result<Object> {
+        let ((object, is_stream), pos) = self.parse_at(offset, |lexer| {
+            lexer.object_header()?;
+            let object = lexer.object()?;
+            let is_stream = lexer.keyword(b"stream");
+            if is_stream {
+                if lexer.peek() == Some(b'\r') {
+                    lexer.pos += 1;
+                }
+                if lexer.peek() == Some(b'\n') {
+                    lexer.pos += 1;
+                }
+            }
+            Some((object, is_stream))
+        })?;
+
+        let dictionary = match object {
+            Object::Dictionary(dictionary) if is_stream => dictionary,
+            object => return Ok(object),
+        };
+        let start = offset + pos as u64;
+        let len = match dictionary.get("Length") {
+            Some(Object::Number(len)) => Some(*len as usize),
+            // The length may itself be an indirect object, but never a
+            // stream.
+            Some(Object::Reference(number)) if !self.resolving_length => {
+                self.resolving_length = true;
+                let len = self.get_object(*number);
+                self.resolving_length = false;
+                len?.as_number().map(|len| len as usize)
+            }
+            _ => None,
+        };
+        let data = match len {
+            Some(len) if len > MAX_OBJECT_LEN => {
+                return Err(Error::Metadata("PDF stream too large".to_string()))
+            }
+            Some(len) => self.read_at(start, len)?,
+            None => self.read_until_endstream(start)?,
+        };
+        Ok(Object::Stream(dictionary, data))
+    }
+
+    fn read_until_endstream(&mut self, start: u64) -> Result<Vec<u8>> {
+        let mut len = CHUNK_SIZE;
+        loop {
+            let mut data = self.read_at(start, len)?;
+            if let Some(end) = find(&data, b"endstream") {
+                data.truncate(end);
+                if data.ends_with(b"\n") {
+                    data.pop();
+                }
+                if data.ends_with(b"\r") {
+                    data.pop();
+                }
+                return Ok(data);
+            }
+            if data.len() < len || len >= MAX_OBJECT_LEN {
+                return Err(Error::Metadata("unterminated PDF stream".to_string()));
+            }
+            len *= 4;
+        }
+    }
+
+    fn get_object(&mut self, number: u32) -> Result<Object> {
+        match self.xref.get(&number).copied() {
+            Some(XrefEntry::Offset(offset)) => self.object_at(offset),
+            Some(XrefEntry::Compressed(stream, index)) => {
+                if !self.object_streams.contains_key(&stream) {
+                    let objects = self.read_object_stream(stream)?;
+                    self.object_streams.insert(stream, objects);
+                }
+                Ok(self.object_streams[&stream]
+                    .get(index)
+                    .cloned()
+                    .unwrap_or(Object::Null))
+            }
+            None => Ok(Object::Null),
+        }
+    }
+
+    fn resolve(&mut self, object: Object) -> Result<Object> {
+        match object {
+            Object::Reference(number) => self.get_object(number),
+            object => Ok(object),
+        }
+    }
+
+    /// Returns the objects compressed in the object stream `number`.
+    fn read_object_stream(&mut self, number: u32) -> Result<Vec<Object>> {
+        // Object streams are never compressed themselves.
+        let (dictionary, data) = match self.xref.get(&number).copied() {
+            Some(XrefEntry::Offset(offset)) => match self.object_at(offset)? {
+                Object::Stream(dictionary, data) => (dictionary, data),
+                _ => return Ok(Vec::new()),
+            },
+            _ => return Ok(Vec::new()),
+        };
+        let data = decode_stream(&dictionary, data)?;
+        let count = dictionary
+            .get("N")
+            .and_then(Object::as_number)
+            .unwrap_or(0.0) as usize;
+        let first = dictionary
+            .get("First")
+            .and_then(Object::as_number)
+            .unwrap_or(0.0) as usize;
+
+        let mut lexer = Lexer::new(&data, 0);
+        let offsets = (0..count)
+            .map_while(|_| {
+                lexer.integer()?;
+                lexer.integer()
+            })
+            .collect::<Vec<_>>();
+        Ok(offsets
+            .into_iter()
+            .map(|offset| {
+                Lexer::new(&data, first.saturating_add(offset as usize))
+                    .object()
+                    .unwrap_or(Object::Null)
+            })
+            .collect())
+    }
+
+    /// Reads the cross-reference sections starting at `startxref`, newest
+    /// first, following `Prev`.
+    fn read_xref(&mut self) -> Result<()> {
+        let tail_len = self.len.min(1024);
+        let tail = self.read_at(self.len - tail_len, tail_len as usize)?;
+        let startxref = rfind(&tail, b"startxref")
+            .ok_or_else(|| Error::Metadata("no cross-reference table found".to_string()))?;
+        let mut offset = Lexer::new(&tail, startxref + 9).integer();
+
+        let mut visited = HashSet::new();
+        while let Some(current) = offset.filter(|offset| visited.insert(*offset)) {
+            let trailer = self.read_xref_section(current)?;
+            // Hybrid files keep the compressed objects in an extra stream.
+            if let Some(xref_stream) = trailer.get("XRefStm").and_then(Object::as_number) {
+                self.read_xref_section(xref_stream as u64)?;
+            }
+            offset = trailer
+                .get("Prev")
+                .and_then(Object::as_number)
+                .map(|prev| prev as u64);
+            for (key, value) in trailer {
+                self.trailer.entry(key).or_insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a cross-reference table or stream and returns its trailer.
+    /// Entries already known from newer sections are kept.
+    fn read_xref_section(&mut self, offset: u64) -> Result<Dictionary> {
+        if self.read_at(offset, 4)? == b"xref" {
+            let ((entries, trailer), _) = self.parse_at(offset, |lexer| {
+                if !lexer.keyword(b"xref") {
+                    return None;
+                }
+                let mut entries = Vec::new();
+                loop {
+                    if lexer.keyword(b"trailer") {
+                        return Some((entries, lexer.object()?));
+                    }
+                    let start = lexer.integer()?;
+                    let end = start.checked_add(lexer.integer()?)?;
+                    for number in start..end {
+                        let offset = lexer.integer()?;
+                        lexer.integer()?;
+                        match lexer.token() {
+                            b"n" => entries.push((u32::try_from(number).ok()?, offset)),
+                            b"f" => (),
+                            _ => return None,
+                        }
+                    }
+                }
+            })?;
+            for (number, offset) in entries {
+                self.xref.entry(number).or_insert(XrefEntry::Offset(offset));
+            }
+            return match trailer {
+                Object::Dictionary(trailer) => Ok(trailer),
+                _ => Err(Error::Metadata("invalid PDF trailer".to_string())),
+            };
+        }
+
+        let (dictionary, data) = match self.object_at(offset)? {
+            Object::Stream(dictionary, data)
+                if dictionary.get("Type").and_then(Object::as_name) == Some("XRef") =>
+            {
+                (dictionary, data)
+            }
+            _ => {
+                return Err(Error::Metadata(
+                    "invalid PDF cross-reference table".to_string(),
+                ))
+            }
+        };
+        let data = decode_stream(&dictionary, data)?;
+        let numbers = |key| match dictionary.get(key) {
+            Some(Object::Array(items)) => items
+                .iter()
+                .filter_map(Object::as_number)
+                .map(|number| number as usize)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let widths: Vec<usize> = numbers("W");
+        let mut index: Vec<usize> = numbers("Index");
+        if index.is_empty() {
+            let size = dictionary.get("Size").and_then(Object::as_number);
+            index = vec![0, size.unwrap_or(0.0) as usize];
+        }
+        // No field needs more than 8 bytes, which also keeps the sum small.
+        let entry_len = widths.iter().sum::<usize>();
+        if widths.len() != 3 || widths.iter().any(|width| *width > 8) || entry_len == 0 {
+            return Err(Error::Metadata(
+                "invalid PDF cross-reference stream".to_string(),
+            ));
+        }
+
+        let mut rows = data.chunks_exact(entry_len);
+        for section in index.chunks_exact(2) {
+            for number in section[0]..section[0].saturating_add(section[1]) {
+                let (number, row) = match (u32::try_from(number), rows.next()) {
+                    (Ok(number), Some(row)) => (number, row),
+                    _ => break,
+                };
+                let mut fields = [0u64; 3];
+                let mut pos = 0;
+                for (field, width) in fields.iter_mut().zip(&widths) {
+                    *field = row[pos..pos + width]
+                        .iter()
+                        .fold(0, |value, byte| value << 8 | u64::from(*byte));
+                    pos += width;
+                }
+                // The type defaults to 1 if its width is 0.
+                if widths[0] == 0 {
+                    fields[0] = 1;
+                }
+                let entry = match fields[0] {
+                    1 => XrefEntry::Offset(fields[1]),
+                    2 => XrefEntry::Compressed(fields[1] as u32, fields[2] as usize),
+                    _ => continue,
+                };
+                self.xref.entry(number).or_insert(entry);
+            }
+        }
+        Ok(dictionary)
+    }
+
+    /// Rebuilds the cross-reference table by scanning the whole file for
+    /// objects, for files whose offsets are broken. The file is scanned in
+    /// overlapping chunks, so it never has to fit into memory.
+    fn rebuild_xref(&mut self) -> Result<()> {
+        self.xref.clear();
+        self.trailer.clear();
+
+        let mut xref_streams = Vec::new();
+        let mut trailer = None;
+        let mut offset = 0;
+        loop {
+            let data = self.read_at(offset, SCAN_CHUNK_SIZE)?;
+            let is_last = data.len() < SCAN_CHUNK_SIZE;
+            // Anything starting in the overlap is found in the next chunk,
+            // where a header at the very start may have been cut off.
+            let start = usize::from(offset > 0);
+            let end = if is_last {
+                data.len()
+            } else {
+                data.len() - SCAN_OVERLAP
+            };
+
+            for (number, pos) in find_objects(&data) {
+                if pos < start || pos >= end {
+                    continue;
+                }
+                self.xref
+                    .insert(number, XrefEntry::Offset(offset + pos as u64));
+                // Files with cross-reference streams have no trailer
+                // keyword, and their streams are needed to find compressed
+                // objects.
+                let mut lexer = Lexer::new(&data, pos);
+                let is_xref = lexer.object_header().is_some()
+                    && lexer
+                        .object()
+                        .as_ref()
+                        .and_then(Object::as_dictionary)
+                        .and_then(|dictionary| dictionary.get("Type"))
+                        .and_then(Object::as_name)
+                        == Some("XRef");
+                if is_xref {
+                    xref_streams.push(offset + pos as u64);
+                }
+            }
+            let keyword_end = (end + b"trailer".len() - 1).min(data.len());
+            match rfind(&data[..keyword_end], b"trailer") {
+                Some(pos) if pos >= start => trailer = Some(offset + pos as u64),
+                _ => (),
+            }
+
+            if is_last {
+                break;
+            }
+            offset += (end - 1) as u64;
+        }
+
+        if let Some(trailer) = trailer {
+            if let Ok((Object::Dictionary(trailer), _)) =
+                self.parse_at(trailer + 7, |lexer| lexer.object())
+            {
+                self.trailer = trailer;
+            }
+        }
+        for offset in xref_streams.into_iter().rev() {
+            if let Ok(trailer) = self.read_xref_section(offset) {
+                for (key, value) in trailer {
+                    self.trailer.entry(key).or_insert(value);
+                }
+            }
+        }
+
+        if self.trailer.contains_key("Root") {
+            Ok(())
+        } else {
+            Err(Error::Metadata("no PDF document catalog found".to_string()))
+        }
+    }
+}
+
+/// Returns the numbers and offsets of all `N G obj` headers in `data`.
+fn find_objects(data: &[u8]) -> Vec<(u32, usize)> {
+    fn skip_back(data: &[u8], start: &mut usize, predicate: fn(u8) -> bool) -> bool {
+        let end = *start;
+        while *start > 0 && predicate(data[*start - 1]) {
+            *start -= 1;
+        }
+        *start < end
+    }
+
+    let mut objects = Vec::new();
+    let mut position = 0;
+    while let Some(found) = find(&data[position..], b"obj") {
+        let keyword = position + found;
+        position = keyword + 3;
+        if data.get(keyword + 3).is_some_and(|byte| is_regular(*byte)) {
+            continue;
+        }
+        let mut start = keyword;
+        if !(skip_back(data, &mut start, is_whitespace)
+            && skip_back(data, &mut start, |byte| byte.is_ascii_digit())
+            && skip_back(data, &mut start, is_whitespace)
+            && skip_back(data, &mut start, |byte| byte.is_ascii_digit()))
+            || (start > 0 && is_regular(data[start - 1]))
+        {
+            continue;
+        }
+        if let Some(number) = Lexer::new(data, start).integer() {
+            objects.push((number as u32, start));
+        }
+    }
+    objects
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum XmpNamespace {
+    Rdf,
+    Dc,
+    Calibre,
+    CalibreSeriesIndex,
+    Prism,
+    Other,
+}
+
+impl XmpNamespace {
+    fn from_uri(uri: Option<&[u8]>) -> Self {
+        match uri {
+            Some(RDF_NAMESPACE) => XmpNamespace::Rdf,
+            Some(xml::DC_NAMESPACE) => XmpNamespace::Dc,
+            Some(CALIBRE_NAMESPACE) => XmpNamespace::Calibre,
+            Some(CALIBRE_SI_NAMESPACE) => XmpNamespace::CalibreSeriesIndex,
+            Some(uri) if uri.starts_with(PRISM_NAMESPACE_PREFIX) => XmpNamespace::Prism,
+            _ => XmpNamespace::Other,
+        }
+    }
+}
+
+/// The metadata found in an XMP packet.
+#[derive(Default)]
+struct Xmp {
+    titles: Vec<(String, String)>,
+    creators: Vec<String>,
+    subjects: Vec<String>,
+    languages: Vec<String>,
+    publisher: String,
+    description: String,
+    title_sort: String,
+    author_sort: String,
+    calibre_series: String,
+    calibre_series_index: String,
+    prism_series: String,
+    prism_series_number: String,
+}
+
+impl Xmp {
+    /// Stores a value of the property `property`; `element` is the element
+    /// holding it, i.e. the property itself, an `rdf:li` or an `rdf:value`.
+    fn set(
+        &mut self,
+        property: (XmpNamespace, &str),
+        element: (XmpNamespace, &str),
+        language: &str,
+        value: String,
+    ) {
+        use XmpNamespace::*;
+
+        match (property, element) {
+            ((Dc, "title"), (Rdf, "li")) => self.titles.push((language.to_string(), value)),
+            ((Dc, "creator"), (Rdf, "li")) => self.creators.push(value),
+            ((Dc, "subject"), (Rdf, "li")) if !self.subjects.contains(&value) => {
+                self.subjects.push(value)
+            }
+            ((Dc, "language"), (Rdf, "li")) => self.languages.push(value),
+            ((Dc, "publisher"), (Rdf, "li")) => self.publisher = value,
+            ((Dc, "description"), (Rdf, "li")) if self.description.is_empty() => {
+                self.description = value
+            }
+            ((Calibre, "title_sort"), _) => self.title_sort = value,
+            ((Calibre, "author_sort"), _) => self.author_sort = value,
+            ((Calibre, "series"), (Calibre, "series") | (Rdf, "value")) => {
+                self.calibre_series = value
+            }
+            ((Calibre, "series"), (CalibreSeriesIndex, "series_index")) => {
+                self.calibre_series_index = value
+            }
+            ((Prism, "seriesTitle"), _) => self.prism_series = value,
+            ((Prism, "seriesNumber"), _) => self.prism_series_number = value,
+            _ => (),
+        }
+    }
+}
+
+fn parse_xmp(xmp: &[u8]) -> Result<Xmp> {
+    let xml = String::from_utf8_lossy(xmp);
+    let mut reader = Reader::from_str(&xml);
+    let mut buf = Vec::new();
+    let mut ns_buf = Vec::new();
+
+    let mut xmp = Xmp::default();
+    // Namespace, name and xml:lang of the open elements.
+    let mut path: Vec<(XmpNamespace, String, String)> = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        let (namespace, event) = reader.read_namespaced_event(&mut buf, &mut ns_buf)?;
+        let namespace = XmpNamespace::from_uri(namespace);
+
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let name = String::from_utf8_lossy(e.local_name()).into_owned();
+                let mut language = path
+                    .last()
+                    .map(|(_, _, language)| language.clone())
+                    .unwrap_or_default();
+                let is_description = namespace == XmpNamespace::Rdf && name == "Description";
+                for attr in e.attributes().flatten() {
+                    let value = xml::decode_text(&attr.value).trim().to_string();
+                    if attr.key == b"xml:lang" {
+                        language = value;
+                    } else if is_description && !value.is_empty() {
+                        // Simple properties may be given as attributes.
+                        let (attr_ns, attr_name) = reader.attribute_namespace(attr.key, &ns_buf);
+                        let attr_ns = XmpNamespace::from_uri(attr_ns);
+                        let attr_name = String::from_utf8_lossy(attr_name).into_owned();
+                        xmp.set(
+                            (attr_ns, &attr_name),
+                            (attr_ns, &attr_name),
+                            &language,
+                            value,
+                        );
+                    }
+                }
+                if matches!(event, Event::Start(_)) {
+                    path.push((namespace, name, language));
+                    text.clear();
+                }
+            }
+            Event::Text(ref e) => text.push_str(&xml::decode_text(e.escaped())),
+            Event::CData(ref e) => text.push_str(&String::from_utf8_lossy(e)),
+            Event::End(_) => {
+                let value = text.trim().to_string();
+                // Properties are the children of rdf:Description.
+                let description = path
+                    .iter()
+                    .rposition(|(ns, name, _)| *ns == XmpNamespace::Rdf && name == "Description");
+                if let (Some(index), false) = (description, value.is_empty()) {
+                    if let (Some(property), Some(element)) = (path.get(index + 1), path.last()) {
+                        xmp.set(
+                            (property.0, &property.1),
+                            (element.0, &element.1),
+                            &element.2,
+                            value,
+                        );
+                    }
+                }
+                path.pop();
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(xmp)
+}
+
+/// Names the security handler of an encrypted PDF.
+fn encryption_scheme(filter: &str) -> String {
+    match filter {
+        "Standard" => "PDF password security".to_string(),
+        "EBX_HANDLER" => "Adobe ADEPT".to_string(),
+        "Adobe.APS" => "Adobe LiveCycle".to_string(),
+        filter => format!("PDF {}", filter),
+    }
+}
+
+fn read_pdf_metadata<R: Read + Seek>(reader: R) -> Result<BookMetadata> {
+    let mut pdf = Pdf::open(reader)?;
+    let mut book_meta = BookMetadata::new();
+
+    if let Some(encrypt) = pdf.trailer.get("Encrypt").cloned() {
+        let encrypt = pdf.resolve(encrypt)?;
+        let filter = encrypt
+            .as_dictionary()
+            .and_then(|encrypt| encrypt.get("Filter"))
+            .and_then(Object::as_name)
+            .unwrap_or("unknown");
+        book_meta.protection = Some(Protection::Drm(encryption_scheme(filter)));
+    }
+
+    // The XMP packet takes precedence over the Info dictionary, which is
+    // deprecated and limited to a few plain fields.
+    let root = pdf.trailer.get("Root").cloned().unwrap_or(Object::Null);
+    let metadata = pdf
+        .resolve(root)?
+        .as_dictionary()
+        .and_then(|catalog| catalog.get("Metadata"))
+        .cloned();
+    let mut xmp = Xmp::default();
+    if let Some(metadata) = metadata {
+        if let Object::Stream(dictionary, data) = pdf.resolve(metadata)? {
+            // A broken or encrypted packet leaves the Info dictionary.
+            if let Ok(parsed) = decode_stream(&dictionary, data).and_then(|data| parse_xmp(&data)) {
+                xmp = parsed;
+            }
+        }
+    }
+
+    let title = xmp
+        .titles
+        .iter()
+        .find(|(language, _)| language == "x-default")
+        .or_else(|| xmp.titles.first());
+    if let Some((_, title)) = title {
+        book_meta.title = title.clone();
+    }
+    book_meta.title_sort = xmp.title_sort;
+    let author_sorts = xmp.author_sort.split(" & ").collect::<Vec<_>>();
+    let has_author_sorts = author_sorts.len() == xmp.creators.len();
+    book_meta.authors = xmp
+        .creators
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| Author {
+            name,
+            firstauthor: if has_author_sorts {
+                author_sorts[i].trim().to_string()
+            } else {
+                String::new()
+            },
+            role: "aut".to_string(),
+            is_creator: true,
+        })
+        .collect();
+    book_meta.genres = xmp.subjects;
+    book_meta.languages = xmp.languages;
+    book_meta.publisher = xmp.publisher;
+    book_meta.description = xmp.description;
+    for (name, index) in [
+        (xmp.calibre_series, xmp.calibre_series_index),
+        (xmp.prism_series, xmp.prism_series_number),
+    ] {
+        if !name.is_empty() {
+            book_meta.collections.push(Collection {
+                name,
+                collection_type: "series".to_string(),
+                index: Series::parse_index(&index),
+            });
+        }
+    }
+    if let Some(collection) = book_meta.collections.first() {
+        book_meta.series = Series {
+            name: collection.name.clone(),
+            index: collection.index,
+        };
+    }
+
+    // The strings of the Info dictionary are encrypted along with the
+    // document.
+    let info = pdf.trailer.get("Info").cloned().unwrap_or(Object::Null);
+    let info = match book_meta.protection {
+        None => pdf.resolve(info)?,
+        Some(_) => Object::Null,
+    };
+    if let Some(info) = info.as_dictionary() {
+        let mut text = |key| -> Result<String> {
+            Ok(
+                match pdf.resolve(info.get(key).cloned().unwrap_or(Object::Null))? {
+                    Object::String(bytes) => decode_text_string(&bytes).trim().to_string(),
+                    _ => String::new(),
+                },
+            )
+        };
+        if book_meta.title.is_empty() {
+            book_meta.title = text("Title")?;
+        }
+        if book_meta.authors.is_empty() {
+            book_meta.authors = text("Author")?
+                .split(';')
+                .flat_map(|authors| authors.split(" & "))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Author {
+                    name: name.to_string(),
+                    firstauthor: String::new(),
+                    role: "aut".to_string(),
+                    is_creator: true,
+                })
+                .collect();
+        }
+        if book_meta.description.is_empty() {
+            book_meta.description = text("Subject")?;
+        }
+    }
+
+    Ok(book_meta)
+}
+
+/// Reads the metadata of a PDF file from its XMP packet and its Info
+/// dictionary, without touching the page content.
+pub fn get_pdf_metadata(filename: &str) -> Result<BookMetadata> {
+    read_pdf_metadata(fs::File::open(filename)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+    use crate::test_util::authors;
+
+    /// Builds a PDF with a classic cross-reference table; the objects are
+    /// numbered from 1.
+    fn pdf(objects: &[Vec<u8>], trailer: &str) -> Vec<u8> {
+        let mut data = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(data.len());
+            data.extend(format!("{} 0 obj\n", i + 1).as_bytes());
+            data.extend(object);
+            data.extend(b"\nendobj\n");
+        }
+        let startxref = data.len();
+        data.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            data.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        data.extend(
+            format!(
+                "trailer\n<< /Size {} {} >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                trailer,
+                startxref
+            )
+            .as_bytes(),
+        );
+        data
+    }
+
+    fn stream(dictionary: &str, data: &[u8]) -> Vec<u8> {
+        let mut stream =
+            format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()).into_bytes();
+        stream.extend(data);
+        stream.extend(b"\nendstream");
+        stream
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_text_strings() {
+        assert_eq!(PDF_DOC_HIGH.chars().count(), 0x21);
+        assert_eq!(decode_text_string(b"Caf\xe9 \x84 \x93"), "Café — ﬁ");
+        assert_eq!(decode_text_string(b"\xfe\xff\x04\x1f\x00!"), "П!");
+        assert_eq!(decode_text_string(b"\xef\xbb\xbf\xc3\xa9"), "é");
+    }
+
+    #[test]
+    fn reads_the_info_dictionary() {
+        let data = pdf(
+            &[
+                b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+                b"<< /Type /Pages /Kids [] /Count 0 >>".to_vec(),
+                br"<< /Title (Nested \(Parens\) and \351scapes) /Author 4 0 R /Subject <48692e> >>"
+                    .to_vec(),
+                b"(Ann Author; Bob Builder & Cy Coder)".to_vec(),
+            ],
+            "/Root 1 0 R /Info 3 0 R",
+        );
+
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Nested (Parens) and éscapes");
+        assert_eq!(
+            authors(&book_meta),
+            [
+                ("Ann Author", "", "aut"),
+                ("Bob Builder", "", "aut"),
+                ("Cy Coder", "", "aut")
+            ]
+        );
+        assert_eq!(book_meta.description, "Hi.");
+        assert_eq!(book_meta.protection, None);
+    }
+
+    #[test]
+    fn prefers_the_xmp_packet() {
+        let xmp = include_bytes!("../tests/pdf/calibre.xmp");
+        let data = pdf(
+            &[
+                b"<< /Type /Catalog /Metadata 2 0 R >>".to_vec(),
+                stream("/Type /Metadata /Subtype /XML", xmp),
+                b"<< /Title (Info Title) /Author (Info Author) /Subject (About) >>".to_vec(),
+            ],
+            "/Root 1 0 R /Info 3 0 R",
+        );
+
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "The & Title");
+        assert_eq!(book_meta.title_sort, "Title, The &");
+        assert_eq!(
+            authors(&book_meta),
+            [
+                ("Jane Doe", "Doe, Jane", "aut"),
+                ("John Roe", "Roe, John", "aut")
+            ]
+        );
+        assert_eq!(book_meta.genres, ["Physics", "Reference"]);
+        assert_eq!(book_meta.languages, ["en"]);
+        assert_eq!(book_meta.series.name, "Lectures");
+        assert_eq!(book_meta.series.index, Some(2.5));
+        assert_eq!(book_meta.collections[1].name, "Other Series");
+        assert_eq!(book_meta.collections[1].index, Some(7.0));
+        assert_eq!(book_meta.description, "About");
+    }
+
+    #[test]
+    fn reads_compressed_objects() {
+        let catalog = "<< /Type /Catalog >> ";
+        let info = "<< /Title (Compressed) /Author (Ann Author) >>";
+        let header = format!("1 0 2 {} ", catalog.len());
+        let objects = format!("{}{}{}", header, catalog, info);
+        let object_stream = stream(
+            &format!(
+                "/Type /ObjStm /N 2 /First {} /Filter /FlateDecode",
+                header.len()
+            ),
+            &deflate(objects.as_bytes()),
+        );
+
+        let mut data = b"%PDF-1.5\n".to_vec();
+        let object_stream_offset = data.len();
+        data.extend(b"3 0 obj\n");
+        data.extend(&object_stream);
+        data.extend(b"\nendobj\n");
+        let xref_offset = data.len();
+
+        // type, offset or object stream (2 bytes), generation or index
+        let rows: [[u8; 4]; 5] = [
+            [0, 0, 0, 0],
+            [2, 0, 3, 0],
+            [2, 0, 3, 1],
+            [1, 0, object_stream_offset as u8, 0],
+            [1, (xref_offset >> 8) as u8, xref_offset as u8, 0],
+        ];
+        // PNG "up" predictor
+        let mut predicted = Vec::new();
+        let mut previous = [0u8; 4];
+        for row in rows {
+            predicted.push(2);
+            predicted.extend(row.iter().zip(previous).map(|(b, p)| b.wrapping_sub(p)));
+            previous = row;
+        }
+        let xref_stream = stream(
+            "/Type /XRef /Size 5 /W [1 2 1] /Root 1 0 R /Info 2 0 R \
+            /Filter /FlateDecode /DecodeParms << /Predictor 12 /Columns 4 >>",
+            &deflate(&predicted),
+        );
+        data.extend(b"4 0 obj\n");
+        data.extend(&xref_stream);
+        data.extend(format!("\nendobj\nstartxref\n{}\n%%EOF\n", xref_offset).as_bytes());
+
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Compressed");
+        assert_eq!(authors(&book_meta), [("Ann Author", "", "aut")]);
+    }
+
+    #[test]
+    fn rebuilds_broken_cross_reference_tables() {
+        let mut data = pdf(
+            &[
+                b"<< /Type /Catalog >>".to_vec(),
+                b"<< /Title (Shifted) >>".to_vec(),
+            ],
+            "/Root 1 0 R /Info 2 0 R",
+        );
+        // an edit which shifts all objects
+        data.splice(9..9, b"% inserted comment\n".iter().copied());
+
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Shifted");
+    }
+
+    #[test]
+    fn detects_encryption() {
+        let data = pdf(
+            &[
+                b"<< /Type /Catalog >>".to_vec(),
+                b"<< /Title <8a7f3e> >>".to_vec(),
+                b"<< /Filter /Standard /V 2 /R 3 >>".to_vec(),
+            ],
+            "/Root 1 0 R /Info 2 0 R /Encrypt 3 0 R",
+        );
+
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(
+            book_meta.protection,
+            Some(Protection::Drm("PDF password security".to_string()))
+        );
+        assert!(book_meta.is_empty());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(
+            read_pdf_metadata(Cursor::new(b"PK\x03\x04".to_vec()))
+                .err()
+                .unwrap()
+                .to_string(),
+            "invalid metadata: not a PDF document"
+        );
+    }
+
+    #[test]
+    fn rebuilds_cross_reference_tables_across_chunks() {
+        let header_pos = SCAN_CHUNK_SIZE - SCAN_OVERLAP;
+        for shift in 0..12 {
+            let mut data = pdf(
+                &[
+                    b"<< /Type /Catalog >>".to_vec(),
+                    b"<< /Title (Far away) >>".to_vec(),
+                ],
+                "/Root 1 0 R /Info 2 0 R",
+            );
+            // move the info dictionary around the end of the first chunk
+            let object = find(&data, b"2 0 obj").unwrap();
+            let padding = header_pos + shift - 6 - object;
+            let mut comment = vec![b'%'; padding - 1];
+            comment.push(b'\n');
+            data.splice(object..object, comment);
+
+            let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+            assert_eq!(book_meta.title, "Far away", "shift {}", shift);
+        }
+    }
+
+    #[test]
+    fn bounds_sizes_taken_from_the_file() {
+        let dictionary = |params: &str| match Lexer::new(params.as_bytes(), 0).object() {
+            Some(Object::Dictionary(dictionary)) => dictionary,
+            _ => unreachable!(),
+        };
+        for params in [
+            "/Columns 0",
+            "/Columns 99999999999999999999",
+            "/Colors 4294967296 /Columns 4294967296",
+        ] {
+            let params = format!(
+                "<< /Filter /FlateDecode /DecodeParms << /Predictor 12 {} >> >>",
+                params
+            );
+            assert!(decode_stream(&dictionary(&params), deflate(b"\x02\x00")).is_err());
+        }
+
+        let mut data = pdf(
+            &[
+                b"<< /Type /Catalog >>".to_vec(),
+                b"<< /Title (Overflow) >>".to_vec(),
+            ],
+            "/Root 1 0 R /Info 2 0 R",
+        );
+        let subsection = find(&data, b"xref\n0 3").unwrap() + 5;
+        data.splice(
+            subsection..subsection + 3,
+            b"18446744073709551615 3".iter().copied(),
+        );
+        let book_meta = read_pdf_metadata(Cursor::new(data)).unwrap();
+        assert_eq!(book_meta.title, "Overflow");
+    }
+
+    #[test]
+    fn rejects_deeply_nested_objects() {
+        let nested = |depth| {
+            let mut root = "[".repeat(depth);
+            root += "<< /Type /Catalog >>";
+            root += &"]".repeat(depth);
+            pdf(
+                &[root.into_bytes(), b"<< /Title (Nested) >>".to_vec()],
+                "/Root 1 0 R /Info 2 0 R",
+            )
+        };
+
+        let book_meta = read_pdf_metadata(Cursor::new(nested(MAX_DEPTH - 1))).unwrap();
+        assert_eq!(book_meta.title, "Nested");
+        assert!(read_pdf_metadata(Cursor::new(nested(2_000_000))).is_err());
+    }
+}
@@ -22,10 +22,11 @@ pub fn dialog(icon: Icon, text: &str, buttons: &[&str]) -> i32 {
         Icon::WLan => "5",
     };
 
-    let res = Command::new(DIALOG_PATH)
+    match Command::new(DIALOG_PATH)
         .args([&[iconstr, "", text], buttons].concat())
         .output()
-        .unwrap();
-
-    res.status.code().unwrap_or(-1)
+    {
+        Ok(res) => res.status.code().unwrap_or(-1),
+        Err(_) => -1,
+    }
 }
@@ -0,0 +1,205 @@
+use rusqlite::Connection;
+
+use crate::error::{Error, Result};
+
+/// Describes the layout of the explorer database of a certain firmware
+/// generation.
+pub struct SchemaProfile {
+    pub version: i32,
+    /// Table (as aliased in queries: `books` or `files`) holding the file
+    /// extension of a book.
+    pub ext_table: &'static str,
+    /// Table holding the hashes used by the explorer to recognize books.
+    pub hash_table: &'static str,
+    /// Column of the hash table holding the hash, if present.
+    pub hash_column: Option<&'static str>,
+}
+
+/// Known database versions:
+/// (first version, last version, ext table, hash table, hash column)
+const PROFILES: &[(i32, i32, &str, &str, &str)] = &[
+    (32, 36, "books", "books_uids", "uid"),
+    (37, 37, "books", "books_fast_hashes", "fast_hash"),
+    (38, 41, "files", "books_fast_hashes", "fast_hash"),
+];
+
+/// Returns the column names of `table` (empty if the table doesn't exist).
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    Ok(conn
+        .prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+impl SchemaProfile {
+    /// Determines the profile of the database behind `conn` and verifies
+    /// that all tables and columns accessed by this program exist.
+    pub fn detect(conn: &Connection) -> Result<Self> {
+        let version: i32 = conn
+            .query_row(r#"SELECT id FROM version"#, [], |r| r.get(0))
+            .map_err(|err| Error::Schema(format!("cannot determine database version ({})", err)))?;
+
+        let (_, _, ext_table, hash_table, hash_column) = PROFILES
+            .iter()
+            .find(|(first, last, _, _, _)| (*first..=*last).contains(&version))
+            .ok_or_else(|| {
+                Error::Schema(format!(
+                    "database version {} is not supported (known versions: {}-{})",
+                    version,
+                    PROFILES[0].0,
+                    PROFILES[PROFILES.len() - 1].1
+                ))
+            })?;
+
+        let mut profile = SchemaProfile {
+            version,
+            ext_table,
+            hash_table,
+            hash_column: None,
+        };
+        profile.verify(conn)?;
+
+        // The hash is only used to recognize moved books, so it's optional.
+        if table_columns(conn, hash_table)?
+            .iter()
+            .any(|c| c == hash_column)
+        {
+            profile.hash_column = Some(hash_column);
+        }
+
+        Ok(profile)
+    }
+
+    fn verify(&self, conn: &Connection) -> Result<()> {
+        let ext_table = match self.ext_table {
+            "files" => "files",
+            _ => "books_impl",
+        };
+
+        let required: &[(&str, &[&str])] = &[
+            (
+                "books_impl",
+                &[
+                    "id",
+                    "title",
+                    "sort_title",
+                    "first_title_letter",
+                    "author",
+                    "firstauthor",
+                    "first_author_letter",
+                    "series",
+                    "numinseries",
+                    "size",
+                ],
+            ),
+            ("files", &["book_id", "folder_id", "filename", "storageid"]),
+            (ext_table, &["ext"]),
+            ("folders", &["id", "storageid", "name"]),
+            ("genres", &["id", "name"]),
+            ("booktogenre", &["bookid", "genreid"]),
+            ("books_settings", &["bookid"]),
+            ("bookshelfs_books", &["bookid"]),
+            ("social", &["bookid"]),
+            (self.hash_table, &["book_id"]),
+        ];
+
+        for (table, columns) in required {
+            let existing = table_columns(conn, table)?;
+
+            if existing.is_empty() {
+                return Err(Error::Schema(format!(
+                    "table {} is missing (database version {})",
+                    table, self.version
+                )));
+            }
+            if let Some(column) = columns.iter().find(|c| !existing.iter().any(|e| e == *c)) {
+                return Err(Error::Schema(format!(
+                    "column {}.{} is missing (database version {})",
+                    table, column, self.version
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::database;
+
+    fn error(conn: &Connection) -> String {
+        SchemaProfile::detect(conn).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn detects_the_supported_versions() {
+        let conn = database();
+        let profile = SchemaProfile::detect(&conn).unwrap();
+        assert_eq!(profile.ext_table, "files");
+        assert_eq!(profile.hash_column, Some("fast_hash"));
+
+        conn.execute("UPDATE version SET id = 41", []).unwrap();
+        assert_eq!(SchemaProfile::detect(&conn).unwrap().version, 41);
+
+        conn.execute_batch(
+            r#"
+            UPDATE version SET id = 32;
+            ALTER TABLE books_impl ADD COLUMN ext TEXT;
+            CREATE TABLE books_uids (book_id INTEGER, uid TEXT);"#,
+        )
+        .unwrap();
+        let profile = SchemaProfile::detect(&conn).unwrap();
+        assert_eq!(profile.version, 32);
+        assert_eq!(profile.ext_table, "books");
+        assert_eq!(profile.hash_table, "books_uids");
+        assert_eq!(profile.hash_column, Some("uid"));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let conn = database();
+        for version in [31, 42] {
+            conn.execute("UPDATE version SET id = ?1", [version])
+                .unwrap();
+            assert_eq!(
+                error(&conn),
+                format!(
+                    "unexpected database schema: database version {} is not supported \
+                    (known versions: 32-41)",
+                    version
+                )
+            );
+        }
+
+        conn.execute("DROP TABLE version", []).unwrap();
+        assert!(error(&conn).contains("cannot determine database version"));
+    }
+
+    #[test]
+    fn rejects_missing_tables_and_columns() {
+        let conn = database();
+        conn.execute("ALTER TABLE books_impl DROP COLUMN series", [])
+            .unwrap();
+        assert_eq!(
+            error(&conn),
+            "unexpected database schema: column books_impl.series is missing (database version 38)"
+        );
+
+        let conn = database();
+        conn.execute("DROP TABLE social", []).unwrap();
+        assert_eq!(
+            error(&conn),
+            "unexpected database schema: table social is missing (database version 38)"
+        );
+    }
+
+    #[test]
+    fn treats_the_hash_column_as_optional() {
+        let conn = database();
+        conn.execute("ALTER TABLE books_fast_hashes DROP COLUMN fast_hash", [])
+            .unwrap();
+        assert_eq!(SchemaProfile::detect(&conn).unwrap().hash_column, None);
+    }
+}
@@ -0,0 +1,105 @@
+//! Helpers shared by the tests of the book readers and the database fixes.
+
+use std::{
+    fs,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use rusqlite::Connection;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+use crate::{format::BookMetadata, journal};
+
+/// Builds a ZIP file in memory from (name, content) pairs.
+pub fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, content) in entries {
+        writer.start_file(*name, FileOptions::default()).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+}
+
+/// Builds a ZIP archive in memory from (name, content) pairs.
+pub fn archive(entries: &[(&str, &str)]) -> ZipArchive<Cursor<Vec<u8>>> {
+    ZipArchive::new(Cursor::new(zip(entries))).unwrap()
+}
+
+/// Returns an empty temporary directory, unique to the test `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pbdbfixer-{}-{}", std::process::id(), name));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes an EPUB with the given `<metadata>` content to `path`.
+pub fn write_epub(path: &Path, metadata: &str) {
+    let opf = format!(
+        r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+          <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"
+              xmlns:opf="http://www.idpf.org/2007/opf">{}</metadata>
+        </package>"#,
+        metadata
+    );
+    fs::write(path, zip(&[("content.opf", &opf)])).unwrap();
+}
+
+/// Name, sort name and role of each author.
+pub fn authors(book_meta: &BookMetadata) -> Vec<(&str, &str, &str)> {
+    book_meta
+        .authors
+        .iter()
+        .map(|a| (a.name.as_str(), a.firstauthor.as_str(), a.role.as_str()))
+        .collect()
+}
+
+/// Creates an in-memory database with the tables of database version 38
+/// which are accessed by the fixes, and attaches an in-memory journal.
+pub fn database() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        r#"
+        CREATE TABLE version (id INTEGER);
+        INSERT INTO version VALUES (38);
+        CREATE TABLE books_impl (
+          id INTEGER PRIMARY KEY,
+          title TEXT,
+          sort_title TEXT,
+          first_title_letter TEXT,
+          author TEXT,
+          firstauthor TEXT,
+          first_author_letter TEXT,
+          series TEXT,
+          numinseries INTEGER,
+          size INTEGER
+        );
+        CREATE TABLE folders (id INTEGER PRIMARY KEY, storageid INTEGER, name TEXT);
+        CREATE TABLE files (
+          id INTEGER PRIMARY KEY,
+          book_id INTEGER,
+          folder_id INTEGER,
+          filename TEXT,
+          storageid INTEGER,
+          ext TEXT
+        );
+        CREATE TABLE genres (id INTEGER PRIMARY KEY, name TEXT UNIQUE);
+        CREATE TABLE booktogenre (bookid INTEGER, genreid INTEGER, PRIMARY KEY (bookid, genreid));
+        CREATE TABLE books_settings (
+          bookid INTEGER,
+          profileid INTEGER,
+          cpage INTEGER,
+          UNIQUE (bookid, profileid)
+        );
+        CREATE TABLE bookshelfs_books (bookshelfid INTEGER, bookid INTEGER, UNIQUE (bookshelfid, bookid));
+        CREATE TABLE social (bookid INTEGER, type INTEGER, value TEXT);
+        CREATE TABLE books_fast_hashes (book_id INTEGER, fast_hash TEXT);
+        ATTACH DATABASE ':memory:' AS journal;"#,
+    )
+    .unwrap();
+    journal::create_tables(&conn).unwrap();
+    conn
+}
@@ -0,0 +1,33 @@
+use quick_xml::escape;
+
+pub const DC_NAMESPACE: &[u8] = b"http://purl.org/dc/elements/1.1/";
+
+/// Entities which are not predefined in XML but occur in metadata written
+/// by HTML-minded tools.
+const HTML_ENTITIES: &[(&str, &str)] = &[
+    ("nbsp", "\u{a0}"),
+    ("ndash", "\u{2013}"),
+    ("mdash", "\u{2014}"),
+    ("lsquo", "\u{2018}"),
+    ("rsquo", "\u{2019}"),
+    ("ldquo", "\u{201c}"),
+    ("rdquo", "\u{201d}"),
+    ("hellip", "\u{2026}"),
+];
+
+pub fn decode(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Unescapes and decodes text or an attribute value. Unknown entities are
+/// kept as they are.
+pub fn decode_text(text: &[u8]) -> String {
+    let custom_entities = HTML_ENTITIES
+        .iter()
+        .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect();
+    match escape::unescape_with(text, &custom_entities) {
+        Ok(text) => decode(&text),
+        Err(_) => decode(text),
+    }
+}